@@ -6,6 +6,8 @@ worker_greylist_time_sec: 120
 default_query_timeout_sec: 60
 summary_print_interval_sec: 0
 workers_update_interval_sec: 60
+worker_urls:
+  allow_unsigned: {{ or .Values.gateway.allow_unsigned_worker_urls (not .Values.gateway.worker_url_secret) }}
 available_datasets:
 {{- range $name, $dataset := .Values.datasets }}
     {{ $name }}: {{ $dataset }}
@@ -1,3 +1,7 @@
 PORT="${HTTP_LISTEN_ADDR##*:}"
 
-yq '.available_datasets.[] | key' "$CONFIG_PATH" | xargs -I % curl -s -f "http://localhost:$PORT/network/%/height" > /dev/null
+if [ -n "$TLS_CERT_PATH" ]; then
+  curl -s -f -k "https://localhost:$PORT/health/ready" > /dev/null
+else
+  curl -s -f "http://localhost:$PORT/health/ready" > /dev/null
+fi
@@ -89,6 +89,20 @@ impl AllocationsManager {
         Ok(())
     }
 
+    /// Return compute units left for each worker in this epoch
+    pub async fn remaining_cus(&self) -> anyhow::Result<Vec<(PeerId, u32)>> {
+        let rows: Vec<(String, u32)> = self
+            .db_exec(|tx| {
+                let mut stmt = tx.prepare(sql::GET_REMAINING)?;
+                let rows = stmt.query_map((), |row| row.try_into())?;
+                rows.collect()
+            })
+            .await?;
+        rows.into_iter()
+            .map(|(worker_id, cus)| Ok((worker_id.parse()?, cus)))
+            .collect()
+    }
+
     /// Return total (available, allocated, spent) compute units
     pub async fn compute_units_summary(&self) -> anyhow::Result<(u32, u32)> {
         let (allocated, spent) = self
@@ -123,6 +137,9 @@ mod sql {
 
     pub const GET_EPOCH: &str = "SELECT COALESCE(MAX(epoch), 0) FROM worker_allocations";
 
+    pub const GET_REMAINING: &str =
+        "SELECT peer_id, allocated_cus - spent_cus FROM worker_allocations";
+
     pub const GET_SUMMARY: &str =
         "SELECT COALESCE(sum(allocated_cus), 0), COALESCE(sum(spent_cus), 0) FROM worker_allocations";
 }
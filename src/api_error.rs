@@ -0,0 +1,333 @@
+use axum::async_trait;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::header::{ACCEPT, CONTENT_TYPE, RETRY_AFTER};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use subsquid_network_transport::PeerId;
+
+use crate::http_server::{QUERY_ID_HEADER, WORKER_ID_HEADER};
+use crate::network_state::WorkerProblem;
+use crate::query::{QueryError, QueryResult};
+use crate::worker_urls::TokenError;
+
+/// Stable error codes clients can rely on, unlike the error messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    UnknownDataset,
+    UnknownWorker,
+    NoData,
+    NoWorker,
+    NoAllocation,
+    WorkerUnavailable,
+    WorkerTimeout,
+    WorkerServerError,
+    BadRequest,
+    QueueFull,
+    QueryDropped,
+    Unauthorized,
+    Forbidden,
+    InvalidToken,
+    TooManyRequests,
+    NotAcceptable,
+    ExecPlanNotFound,
+    InternalError,
+}
+
+/// Error returned by the HTTP API. Serialized as JSON for clients accepting `application/json`,
+/// and as the plain text message for everyone else (see [`negotiate`]).
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    code: ErrorCode,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    worker_id: Option<String>,
+    /// Seconds after which the client may retry, sent in the `Retry-After` header
+    #[serde(skip)]
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: ErrorCode, message: impl ToString) -> Self {
+        Self {
+            status,
+            code,
+            message: message.to_string(),
+            query_id: None,
+            worker_id: None,
+            retry_after: None,
+        }
+    }
+
+    /// Request rejected by an extractor, e.g. because of an invalid path or query parameter
+    fn rejected(status: StatusCode, message: String) -> Self {
+        let code = if status.is_server_error() {
+            ErrorCode::InternalError
+        } else {
+            ErrorCode::BadRequest
+        };
+        Self::new(status, code, message)
+    }
+
+    pub fn unknown_dataset(dataset: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::UnknownDataset,
+            format!("Unknown dataset: {dataset}"),
+        )
+    }
+
+    pub fn unknown_worker(worker_id: PeerId) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::UnknownWorker,
+            format!("Unknown worker: {worker_id}"),
+        )
+        .with_worker_id(worker_id)
+    }
+
+    pub fn no_data(dataset: &str) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::NoData,
+            format!("No data for dataset {dataset}"),
+        )
+    }
+
+    pub fn no_worker(dataset: &str, block: u32) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::NoWorker,
+            format!("No available worker for dataset {dataset} block {block}"),
+        )
+    }
+
+    /// The worker chosen by the client shouldn't get the query
+    pub fn worker_unavailable(worker_id: PeerId, problem: WorkerProblem) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::WorkerUnavailable,
+            format!("Worker {worker_id} cannot process the query: {problem}"),
+        )
+        .with_worker_id(worker_id)
+    }
+
+    /// The worker URL is unsigned, expired or signed for a different query
+    pub fn invalid_token(error: TokenError) -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::InvalidToken,
+            format!("Invalid worker URL token: {error}. Get a new URL from the worker endpoint"),
+        )
+    }
+
+    pub fn bad_request(message: impl ToString) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, ErrorCode::BadRequest, message)
+    }
+
+    pub fn not_acceptable() -> Self {
+        Self::new(
+            StatusCode::NOT_ACCEPTABLE,
+            ErrorCode::NotAcceptable,
+            "None of the encodings in Accept-Encoding is supported",
+        )
+    }
+
+    pub fn internal(message: impl ToString) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            message,
+        )
+    }
+
+    /// Error for a query which didn't return data
+    pub fn from_result(result: &QueryResult) -> Self {
+        let code = result.error_code().unwrap_or(ErrorCode::InternalError);
+        Self::new(result.status_code(), code, result)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn with_query_id(mut self, query_id: impl ToString) -> Self {
+        self.query_id = Some(query_id.to_string());
+        self
+    }
+
+    pub fn with_worker_id(mut self, worker_id: PeerId) -> Self {
+        self.worker_id = Some(worker_id.to_string());
+        self
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(err: QueryError) -> Self {
+        match err {
+            // The query queue is full only for a moment of overload
+            QueryError::QueueFull => {
+                Self::new(StatusCode::SERVICE_UNAVAILABLE, ErrorCode::QueueFull, err)
+                    .with_retry_after(1)
+            }
+            QueryError::Dropped => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::QueryDropped,
+                err,
+            ),
+        }
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+/// Same as [`axum::extract::Path`], but rejects requests with an [`ApiError`]
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Path(value) =
+            axum::extract::Path::from_request_parts(parts, state).await?;
+        Ok(Path(value))
+    }
+}
+
+/// Same as [`axum::extract::Query`], but rejects requests with an [`ApiError`]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) =
+            axum::extract::Query::from_request_parts(parts, state).await?;
+        Ok(Query(value))
+    }
+}
+
+/// Same as the [`Json`] extractor, but rejects requests with an [`ApiError`]
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::from_request(req, state).await?;
+        Ok(JsonBody(value))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(&self)).into_response();
+        if let Some(value) = self
+            .query_id
+            .as_ref()
+            .and_then(|query_id| HeaderValue::from_str(query_id).ok())
+        {
+            response.headers_mut().insert(QUERY_ID_HEADER, value);
+        }
+        if let Some(value) = self
+            .worker_id
+            .as_ref()
+            .and_then(|worker_id| HeaderValue::from_str(worker_id).ok())
+        {
+            response.headers_mut().insert(WORKER_ID_HEADER, value);
+        }
+        if let Some(secs) = self.retry_after {
+            response.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Middleware replacing JSON error bodies with plain text messages,
+/// unless the client explicitly accepts `application/json`
+pub async fn negotiate(req: Request, next: Next) -> Response {
+    let json_accepted = accepts_json(req.headers());
+    let response = next.run(req).await;
+    if json_accepted {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    match parts.extensions.remove::<ApiError>() {
+        Some(err) => {
+            parts.headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            );
+            (parts, err.message).into_response()
+        }
+        None => Response::from_parts(parts, body),
+    }
+}
+
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_range| {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default();
+            let rejected = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            media_type.eq_ignore_ascii_case("application/json") && !rejected
+        })
+}
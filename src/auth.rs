@@ -0,0 +1,75 @@
+use axum::extract::{Request, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::api_error::{ApiError, ErrorCode};
+use crate::config::{Config, Scope};
+use crate::metrics;
+use crate::tls::TlsClient;
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// ID of the API key the request was authenticated with
+#[derive(Debug, Clone)]
+pub struct ClientId(pub String);
+
+/// Middleware checking that the request carries an API key granted the given scope,
+/// or comes with a client certificate mapped to such a key.
+/// Authentication is disabled if no API keys are configured.
+pub async fn authenticate(State(scope): State<Scope>, mut req: Request, next: Next) -> Response {
+    let config = Config::get();
+    if config.api_keys.is_empty() {
+        return next.run(req).await;
+    }
+
+    let api_key = extract_key(req.headers())
+        .and_then(|key| config.api_key(key))
+        .or_else(|| {
+            let subject = req.extensions().get::<TlsClient>()?.cert_subject.as_ref()?;
+            config.api_key_by_cert(subject)
+        });
+    let api_key = match api_key {
+        Some(api_key) => api_key,
+        None => {
+            let err = ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::Unauthorized,
+                "Missing or invalid API key",
+            );
+            return ([(WWW_AUTHENTICATE, "Bearer")], err).into_response();
+        }
+    };
+    if !api_key.scopes.contains(&scope) {
+        log::debug!(
+            "API key {} not allowed to access {} ({scope} scope)",
+            api_key.id,
+            req.uri().path()
+        );
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::Forbidden,
+            format!("API key {} has no {scope} scope", api_key.id),
+        )
+        .into_response();
+    }
+
+    log::debug!(
+        "{} {} key_id={}",
+        req.method(),
+        req.uri().path(),
+        api_key.id
+    );
+    metrics::authenticated_request(&api_key.id, &scope.to_string());
+    req.extensions_mut().insert(ClientId(api_key.id.clone()));
+    next.run(req).await
+}
+
+/// Key can be passed either as a bearer token or in the `x-api-key` header
+fn extract_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(value) = headers.get(AUTHORIZATION) {
+        return value.to_str().ok()?.strip_prefix("Bearer ").map(str::trim);
+    }
+    headers.get(API_KEY_HEADER)?.to_str().ok()
+}
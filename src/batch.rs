@@ -0,0 +1,299 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::http::StatusCode;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use utoipa::ToSchema;
+
+use subsquid_messages::OkResult;
+
+use crate::api_error::{ApiError, ErrorCode};
+use crate::client::{QueryClient, RoutedQueryResult};
+use crate::config::DatasetId;
+use crate::encoding::decode_gzip;
+use crate::query::{QueryRange, QueryResult};
+
+/// Outcome of a single query of the batch, sent as one line of the response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchItem {
+    /// Position of the query in the request
+    index: usize,
+    /// HTTP status the query would get if sent on its own
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    worker_id: Option<String>,
+    /// Query result, present if the query succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    data: Option<Box<RawValue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
+}
+
+impl BatchItem {
+    fn error(index: usize, error: ApiError) -> Self {
+        Self {
+            index,
+            status: error.status().as_u16(),
+            query_id: None,
+            worker_id: None,
+            data: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// Executes independent queries over the same dataset, at most `concurrency` at a time.
+/// All of them share the same deadline, and every result is emitted as soon as it arrives,
+/// so the items may come in a different order than the queries.
+pub struct Batch {
+    client: Arc<QueryClient>,
+    dataset: String,
+    dataset_id: DatasetId,
+    queries: Vec<Box<RawValue>>,
+    deadline: Instant,
+    concurrency: usize,
+    /// Sent by the client, linked to the IDs of all queries
+    request_id: Option<String>,
+}
+
+impl Batch {
+    pub fn new(
+        client: Arc<QueryClient>,
+        dataset: String,
+        dataset_id: DatasetId,
+        queries: Vec<Box<RawValue>>,
+        deadline: Instant,
+        concurrency: usize,
+    ) -> Self {
+        Self {
+            client,
+            dataset,
+            dataset_id,
+            queries,
+            deadline,
+            concurrency,
+            request_id: None,
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: Option<String>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<Vec<u8>, Infallible>> {
+        let Self {
+            client,
+            dataset,
+            dataset_id,
+            queries,
+            deadline,
+            concurrency,
+            request_id,
+        } = self;
+        let dataset = Arc::new(dataset);
+        stream_items(queries, concurrency, move |index, query| {
+            let client = client.clone();
+            let dataset = dataset.clone();
+            let dataset_id = dataset_id.clone();
+            let request_id = request_id.clone();
+            async move {
+                execute(
+                    index, client, &dataset, dataset_id, query, deadline, request_id,
+                )
+                .await
+            }
+        })
+    }
+}
+
+/// Runs `execute` for every query, at most `concurrency` at a time,
+/// and serializes the items in the order of completion
+fn stream_items<F, Fut>(
+    queries: Vec<Box<RawValue>>,
+    concurrency: usize,
+    execute: F,
+) -> impl Stream<Item = Result<Vec<u8>, Infallible>>
+where
+    F: Fn(usize, String) -> Fut,
+    Fut: Future<Output = Result<BatchItem, ApiError>>,
+{
+    futures::stream::iter(queries.into_iter().enumerate())
+        .map(move |(index, query)| {
+            let item = execute(index, query.get().to_string());
+            async move {
+                item.await
+                    .unwrap_or_else(|err| BatchItem::error(index, err))
+            }
+        })
+        .buffer_unordered(concurrency)
+        .map(|item| {
+            let mut line = serde_json::to_vec(&item).expect("Batch item is serializable");
+            line.push(b'\n');
+            Ok(line)
+        })
+}
+
+async fn execute(
+    index: usize,
+    client: Arc<QueryClient>,
+    dataset: &str,
+    dataset_id: DatasetId,
+    query: String,
+    deadline: Instant,
+    request_id: Option<String>,
+) -> Result<BatchItem, ApiError> {
+    let start_block = QueryRange::parse(&query)
+        .map_err(|err| ApiError::bad_request(format!("Invalid query: {err}")))?
+        .from_block;
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(ApiError::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::WorkerTimeout,
+            "Batch deadline exceeded before the query was sent",
+        ));
+    }
+
+    let RoutedQueryResult {
+        query_id,
+        result,
+        tried_workers,
+        ..
+    } = client
+        .execute_routed_query(
+            request_id,
+            dataset_id,
+            query,
+            start_block,
+            Some(remaining),
+            false,
+        )
+        .await?
+        .ok_or_else(|| ApiError::no_worker(dataset, start_block))?;
+
+    let worker_id = *tried_workers.last().expect("Query was sent to a worker");
+    let data = match result {
+        QueryResult::Ok(OkResult { data, .. }) => decode_gzip(data)
+            .ok()
+            .and_then(|data| RawValue::from_string(String::from_utf8(data).ok()?).ok())
+            .ok_or_else(|| ApiError::internal("Worker returned an invalid result")),
+        res => Err(ApiError::from_result(&res)),
+    }
+    .map_err(|err| err.with_query_id(&query_id.id).with_worker_id(worker_id))?;
+
+    Ok(BatchItem {
+        index,
+        status: StatusCode::OK.as_u16(),
+        query_id: Some(query_id.id),
+        worker_id: Some(worker_id.to_string()),
+        data: Some(data),
+        error: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    fn queries(n: usize) -> Vec<Box<RawValue>> {
+        (0..n)
+            .map(|i| RawValue::from_string(format!("{{\"fromBlock\":{i}}}")).unwrap())
+            .collect()
+    }
+
+    fn ok_item(index: usize, query: String) -> BatchItem {
+        BatchItem {
+            index,
+            status: StatusCode::OK.as_u16(),
+            query_id: Some(format!("query-{index}")),
+            worker_id: None,
+            data: Some(RawValue::from_string(query).unwrap()),
+            error: None,
+        }
+    }
+
+    async fn collect_lines(stream: impl Stream<Item = Result<Vec<u8>, Infallible>>) -> Vec<Value> {
+        stream
+            .map(|line| serde_json::from_slice(&line.unwrap()).unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn concurrency_limited() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let stream = stream_items(queries(10), 3, |index, query| {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            async move {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(ok_item(index, query))
+            }
+        });
+        let lines = collect_lines(stream).await;
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+        let mut indexes: Vec<_> = lines
+            .iter()
+            .map(|line| line["index"].as_u64().unwrap())
+            .collect();
+        indexes.sort();
+        assert_eq!(indexes, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn items_emitted_in_order_of_completion() {
+        let stream = stream_items(queries(2), 2, |index, query| async move {
+            // The first query takes longer
+            let delay = if index == 0 { 50 } else { 0 };
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            Ok(ok_item(index, query))
+        });
+        let lines = collect_lines(stream).await;
+        assert_eq!(lines[0]["index"], 1);
+        assert_eq!(lines[1]["index"], 0);
+    }
+
+    #[tokio::test]
+    async fn errors_reported_per_item() {
+        let stream = stream_items(queries(4), 4, |index, query| async move {
+            if index % 2 == 1 {
+                Err(ApiError::bad_request("Invalid query").with_query_id("failed"))
+            } else {
+                Ok(ok_item(index, query))
+            }
+        });
+        let lines = collect_lines(stream).await;
+        assert_eq!(lines.len(), 4);
+        for line in lines {
+            let index = line["index"].as_u64().unwrap();
+            if index % 2 == 1 {
+                assert_eq!(line["status"], 400);
+                assert_eq!(line["error"]["code"], "bad_request");
+                assert_eq!(line["error"]["query_id"], "failed");
+                assert!(line.get("data").is_none());
+            } else {
+                assert_eq!(line["status"], 200);
+                assert_eq!(line["data"]["fromBlock"], index);
+                assert!(line.get("error").is_none());
+            }
+        }
+    }
+}
@@ -40,6 +40,10 @@ impl ChainUpdatesHandler {
         if current_epoch == last_epoch {
             let (allocated, spent) = alloc_manager.compute_units_summary().await?;
             log::info!("allocated CU: {allocated} spent CU: {spent}");
+            let remaining_cus = alloc_manager.remaining_cus().await?;
+            let mut network_state = self.network_state.write().await;
+            network_state.update_remaining_cus(remaining_cus);
+            network_state.chain_updated();
             return Ok(());
         }
 
@@ -55,9 +59,12 @@ impl ChainUpdatesHandler {
         alloc_manager
             .update_allocations(allocations, current_epoch)
             .await?;
+        let remaining_cus = alloc_manager.remaining_cus().await?;
         let mut network_state = self.network_state.write().await;
         network_state.update_registered_workers(workers);
         network_state.reset_allocations_cache();
+        network_state.update_remaining_cus(remaining_cus);
+        network_state.chain_updated();
 
         let (allocated, spent) = alloc_manager.compute_units_summary().await?;
         log::info!("Updating workers and allocations complete. allocated CU: {allocated} spent CU: {spent}");
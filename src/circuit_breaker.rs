@@ -0,0 +1,342 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+use serde_with::{serde_as, TimestampSeconds};
+use utoipa::ToSchema;
+
+use crate::config::CircuitBreakerConfig;
+
+/// Number of the most recent state changes kept for each worker
+const HISTORY_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Queries are sent to the worker as usual
+    Closed,
+    /// The worker is greylisted after a failed query
+    Open,
+    /// The open time has passed and a few trial queries are let through
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Query failure blamed on the worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Failure {
+    Timeout,
+    ServerError,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BreakerEvent {
+    /// Unix timestamp in seconds
+    #[serde_as(as = "TimestampSeconds<i64>")]
+    #[schema(value_type = i64)]
+    pub at: SystemTime,
+    /// State the breaker has switched to
+    pub state: BreakerState,
+    /// Failure which has opened the breaker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<Failure>,
+    /// How long the breaker stays open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_sec: Option<u64>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BreakerSummary {
+    state: BreakerState,
+    /// Unix timestamp in seconds when the breaker becomes half-open
+    #[serde_as(as = "Option<TimestampSeconds<i64>>")]
+    #[schema(value_type = Option<i64>)]
+    open_until: Option<SystemTime>,
+    /// Number of times the breaker has opened in a row, which determines the next open time
+    consecutive_opens: u32,
+    /// Successful trial queries since the breaker became half-open
+    trial_successes: u32,
+    /// Trial queries sent and not finished yet
+    trials_in_flight: usize,
+    /// Latest state changes, oldest first
+    history: Vec<BreakerEvent>,
+}
+
+/// Keeps failing workers greylisted for exponentially growing periods of time.
+/// After each period, the worker only gets `half_open_trials` trial queries at a time,
+/// and is fully restored once that many of them have succeeded.
+/// While half-open, only the outcomes of trial queries are counted.
+#[derive(Debug, Default, Clone)]
+pub struct CircuitBreaker {
+    open_until: Option<SystemTime>,
+    consecutive_opens: u32,
+    trial_successes: u32,
+    /// IDs of the trial queries sent while half-open
+    trials: HashSet<String>,
+    /// Time of the last full restore
+    closed_at: Option<SystemTime>,
+    history: VecDeque<BreakerEvent>,
+}
+
+impl CircuitBreaker {
+    pub fn state(&self) -> BreakerState {
+        match self.open_until {
+            None => BreakerState::Closed,
+            Some(until) if SystemTime::now() < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether no more trial queries can be sent until one of them finishes
+    pub fn trials_exhausted(&self, config: &CircuitBreakerConfig) -> bool {
+        self.trials.len() >= config.half_open_trials as usize
+    }
+
+    /// Records the query as a trial if the breaker is half-open and has a free trial slot.
+    /// Returns true if the query is a trial.
+    pub fn query_started(&mut self, query_id: &str, config: &CircuitBreakerConfig) -> bool {
+        if self.state() != BreakerState::HalfOpen || self.trials_exhausted(config) {
+            return false;
+        }
+        self.trials.insert(query_id.to_string())
+    }
+
+    /// Forgets the query if it's a trial which has finished without a result,
+    /// e.g. because it has been dropped
+    pub fn query_finished(&mut self, query_id: &str) {
+        self.trials.remove(query_id);
+    }
+
+    /// Returns the open time if the breaker has been opened
+    pub fn failure(
+        &mut self,
+        query_id: &str,
+        failure: Failure,
+        config: &CircuitBreakerConfig,
+    ) -> Option<Duration> {
+        let now = SystemTime::now();
+        match self.state() {
+            // A query may be sent to a greylisted worker if no other one is available
+            BreakerState::Open => return None,
+            // Queries sent before the breaker became half-open are not trials
+            BreakerState::HalfOpen if !self.trials.contains(query_id) => return None,
+            // Failures long after the last restore don't make the open time longer
+            BreakerState::Closed
+                if self
+                    .closed_at
+                    .is_some_and(|t| t + config.max_open_time < now) =>
+            {
+                self.consecutive_opens = 0;
+            }
+            BreakerState::Closed | BreakerState::HalfOpen => {}
+        }
+        let open_time = config.open_time(self.consecutive_opens);
+        self.open_until = Some(now + open_time);
+        self.consecutive_opens = self.consecutive_opens.saturating_add(1);
+        self.trial_successes = 0;
+        self.trials.clear();
+        self.record(BreakerEvent {
+            at: now,
+            state: BreakerState::Open,
+            failure: Some(failure),
+            open_sec: Some(open_time.as_secs()),
+        });
+        Some(open_time)
+    }
+
+    /// Returns true if the breaker has been closed
+    pub fn success(&mut self, query_id: &str, config: &CircuitBreakerConfig) -> bool {
+        if self.state() != BreakerState::HalfOpen || !self.trials.remove(query_id) {
+            return false;
+        }
+        self.trial_successes += 1;
+        if self.trial_successes < config.half_open_trials {
+            return false;
+        }
+        self.close();
+        true
+    }
+
+    /// Closes the breaker and forgets previous failures
+    pub fn reset(&mut self) {
+        if self.open_until.is_some() {
+            self.close();
+        }
+        self.consecutive_opens = 0;
+    }
+
+    fn close(&mut self) {
+        let now = SystemTime::now();
+        self.open_until = None;
+        self.trial_successes = 0;
+        self.trials.clear();
+        self.closed_at = Some(now);
+        self.record(BreakerEvent {
+            at: now,
+            state: BreakerState::Closed,
+            failure: None,
+            open_sec: None,
+        });
+    }
+
+    fn record(&mut self, event: BreakerEvent) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(event);
+    }
+
+    pub fn summary(&self) -> BreakerSummary {
+        BreakerSummary {
+            state: self.state(),
+            open_until: self.open_until,
+            consecutive_opens: self.consecutive_opens,
+            trial_successes: self.trial_successes,
+            trials_in_flight: self.trials.len(),
+            history: self.history.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            initial_open_time: Some(Duration::from_secs(10)),
+            max_open_time: Duration::from_secs(35),
+            backoff_factor: 2.0,
+            half_open_trials: 2,
+        }
+    }
+
+    /// Makes the open time pass
+    fn expire(breaker: &mut CircuitBreaker) {
+        breaker.open_until = Some(SystemTime::now() - Duration::from_secs(1));
+    }
+
+    #[test]
+    fn open_time_grows_up_to_max() {
+        let config = config();
+        let mut breaker = CircuitBreaker::default();
+        let mut open_times = Vec::new();
+        for i in 0..4 {
+            let query_id = format!("trial-{i}");
+            // Every failure after the first one is a failed trial
+            if i > 0 {
+                expire(&mut breaker);
+                assert!(breaker.query_started(&query_id, &config));
+            }
+            open_times.push(breaker.failure(&query_id, Failure::Timeout, &config));
+            assert_eq!(breaker.state(), BreakerState::Open);
+        }
+        let secs = |secs| Some(Duration::from_secs(secs));
+        assert_eq!(open_times, vec![secs(10), secs(20), secs(35), secs(35)]);
+    }
+
+    #[test]
+    fn open_time_saturates() {
+        let mut config = config();
+        config.backoff_factor = 1e300;
+        assert_eq!(config.open_time(u32::MAX), Duration::from_secs(35));
+        config.initial_open_time = Some(Duration::ZERO);
+        assert_eq!(config.open_time(u32::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn failures_ignored_while_open() {
+        let config = config();
+        let mut breaker = CircuitBreaker::default();
+        assert!(breaker.failure("a", Failure::Timeout, &config).is_some());
+        assert_eq!(breaker.failure("b", Failure::ServerError, &config), None);
+        assert_eq!(breaker.summary().consecutive_opens, 1);
+    }
+
+    #[test]
+    fn trial_failure_reopens() {
+        let config = config();
+        let mut breaker = CircuitBreaker::default();
+        breaker.failure("a", Failure::Timeout, &config);
+        expire(&mut breaker);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        // Queries sent before the breaker became half-open don't count
+        assert_eq!(breaker.failure("a", Failure::Timeout, &config), None);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        assert!(breaker.query_started("b", &config));
+        assert_eq!(
+            breaker.failure("b", Failure::ServerError, &config),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.summary().trials_in_flight, 0);
+    }
+
+    #[test]
+    fn trial_successes_close() {
+        let config = config();
+        let mut breaker = CircuitBreaker::default();
+        breaker.failure("a", Failure::Timeout, &config);
+        assert!(!breaker.success("a", &config));
+        expire(&mut breaker);
+
+        assert!(breaker.query_started("b", &config));
+        assert!(breaker.query_started("c", &config));
+        assert!(!breaker.query_started("d", &config));
+        assert!(breaker.trials_exhausted(&config));
+
+        // Only trial queries count
+        assert!(!breaker.success("a", &config));
+        assert!(!breaker.success("b", &config));
+        assert!(!breaker.trials_exhausted(&config));
+        assert!(breaker.success("c", &config));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        let states: Vec<_> = breaker.history.iter().map(|event| event.state).collect();
+        assert_eq!(states, vec![BreakerState::Open, BreakerState::Closed]);
+    }
+
+    #[test]
+    fn unfinished_trials_free_slots() {
+        let config = config();
+        let mut breaker = CircuitBreaker::default();
+        breaker.failure("a", Failure::Timeout, &config);
+        expire(&mut breaker);
+        assert!(breaker.query_started("b", &config));
+        assert!(breaker.query_started("c", &config));
+        breaker.query_finished("b");
+        assert!(breaker.query_started("d", &config));
+        assert!(!breaker.success("b", &config));
+    }
+
+    #[test]
+    fn reset_forgets_failures() {
+        let config = config();
+        let mut breaker = CircuitBreaker::default();
+        breaker.failure("a", Failure::Timeout, &config);
+        expire(&mut breaker);
+        assert!(breaker.query_started("b", &config));
+        breaker.failure("b", Failure::Timeout, &config);
+
+        breaker.reset();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(
+            breaker.failure("c", Failure::Timeout, &config),
+            Some(Duration::from_secs(10))
+        );
+    }
+}
@@ -1,8 +1,9 @@
 use futures::Stream;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::{mpsc, oneshot, RwLock};
 
 use contract_client::Client as ContractClient;
@@ -13,13 +14,55 @@ use subsquid_network_transport::{GatewayEvent, GatewayTransportHandle};
 use crate::allocations::AllocationsManager;
 use crate::chain_updates::ChainUpdatesHandler;
 use crate::config::{Config, DatasetId};
-use crate::network_state::NetworkState;
-use crate::query::{Query, QueryResult};
+use crate::network_state::{NetworkState, WorkerProblem};
+use crate::query::{Query, QueryError, QueryId, QueryResponse, QueryResult};
 use crate::server::Server;
 
+/// Result of a query routed by the gateway, possibly after a few retries
+pub struct RoutedQueryResult {
+    /// ID of the last query sent
+    pub query_id: QueryId,
+    pub result: QueryResult,
+    /// Execution time of the last query
+    pub exec_time: Option<Duration>,
+    pub tried_workers: Vec<PeerId>,
+}
+
+/// Notifies the server if the query future is dropped before the result arrives,
+/// e.g. because the HTTP client has disconnected
+struct CancelGuard<'a> {
+    query_id: Option<String>,
+    cancel_sender: &'a mpsc::Sender<String>,
+}
+
+impl<'a> CancelGuard<'a> {
+    fn new(query_id: String, cancel_sender: &'a mpsc::Sender<String>) -> Self {
+        Self {
+            query_id: Some(query_id),
+            cancel_sender,
+        }
+    }
+
+    fn disarm(mut self) {
+        self.query_id = None;
+    }
+}
+
+impl Drop for CancelGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(query_id) = self.query_id.take() {
+            self.cancel_sender
+                .try_send(query_id)
+                .unwrap_or_else(|e| log::warn!("Cannot cancel query: {e}"));
+        }
+    }
+}
+
 pub struct QueryClient {
     network_state: Arc<RwLock<NetworkState>>,
     query_sender: mpsc::Sender<Query>,
+    cancel_sender: mpsc::Sender<String>,
+    probe_sender: mpsc::Sender<oneshot::Sender<()>>,
     _task_manager: TaskManager,
 }
 
@@ -27,9 +70,11 @@ impl QueryClient {
     pub fn new<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static>(
         network_state: Arc<RwLock<NetworkState>>,
         query_sender: mpsc::Sender<Query>,
+        cancel_sender: mpsc::Sender<String>,
         chain_updates_handler: ChainUpdatesHandler,
         server: Server<S>,
     ) -> Self {
+        let probe_sender = server.probe_sender();
         let mut task_manager = TaskManager::default();
         task_manager.spawn(|c| server.run(c));
 
@@ -48,14 +93,68 @@ impl QueryClient {
         Self {
             network_state,
             query_sender,
+            cancel_sender,
+            probe_sender,
             _task_manager: task_manager,
         }
     }
 
+    /// Check that the query server loop responds within the timeout
+    pub async fn server_responsive(&self, timeout: Duration) -> bool {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        if self.probe_sender.try_send(reply_sender).is_err() {
+            return false;
+        }
+        matches!(
+            tokio::time::timeout(timeout, reply_receiver).await,
+            Ok(Ok(()))
+        )
+    }
+
     pub async fn get_height(&self, dataset_id: &DatasetId) -> Option<u32> {
         self.network_state.read().await.get_height(dataset_id)
     }
 
+    /// Wait until the indexable height of the dataset reaches `target_block`
+    /// or the timeout expires, then return the current height
+    pub async fn wait_for_height(
+        &self,
+        dataset_id: &DatasetId,
+        target_block: u32,
+        timeout: Duration,
+    ) -> Option<u32> {
+        let dataset = Config::get().dataset_name(dataset_id)?;
+        let mut receiver = {
+            let state = self.network_state.read().await;
+            let height = state.get_height(dataset_id);
+            if height.is_some_and(|height| height >= target_block) {
+                return height;
+            }
+            state.subscribe_heights()
+        };
+        let reached = async {
+            loop {
+                match receiver.recv().await {
+                    Ok(update) if update.dataset == dataset => {
+                        if update.height.highest_indexable_block >= target_block {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {
+                        let height = self.get_height(dataset_id).await;
+                        if height.is_some_and(|height| height >= target_block) {
+                            return;
+                        }
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        };
+        let _ = tokio::time::timeout(timeout, reached).await;
+        self.get_height(dataset_id).await
+    }
+
     pub async fn find_worker(&self, dataset_id: &DatasetId, start_block: u32) -> Option<PeerId> {
         self.network_state
             .read()
@@ -63,19 +162,45 @@ impl QueryClient {
             .find_worker(dataset_id, start_block)
     }
 
+    pub async fn check_worker(
+        &self,
+        worker_id: &PeerId,
+        dataset_id: &DatasetId,
+        block: Option<u32>,
+    ) -> Option<WorkerProblem> {
+        self.network_state
+            .read()
+            .await
+            .check_worker(worker_id, dataset_id, block)
+    }
+
+    pub async fn worker_range_end(
+        &self,
+        dataset_id: &DatasetId,
+        worker_id: &PeerId,
+        block: u32,
+    ) -> Option<u32> {
+        self.network_state
+            .read()
+            .await
+            .worker_range_end(dataset_id, worker_id, block)
+    }
+
     pub async fn execute_query(
         &self,
+        query_id: QueryId,
         dataset_id: DatasetId,
         query: String,
         worker_id: PeerId,
         timeout: Option<impl Into<Duration>>,
         profiling: bool,
-    ) -> anyhow::Result<QueryResult> {
+    ) -> Result<QueryResponse, QueryError> {
         let timeout = timeout
             .map(Into::into)
             .unwrap_or(Config::get().default_query_timeout);
         let (result_sender, result_receiver) = oneshot::channel();
         let query = Query {
+            query_id: query_id.clone(),
             dataset_id,
             query,
             worker_id,
@@ -85,10 +210,86 @@ impl QueryClient {
         };
         self.query_sender
             .try_send(query)
-            .map_err(|_| anyhow::anyhow!("Cannot send query"))?;
-        result_receiver
-            .await
-            .map_err(|_| anyhow::anyhow!("Query dropped"))
+            .map_err(|_| QueryError::QueueFull)?;
+        let cancel_guard = CancelGuard::new(query_id.id, &self.cancel_sender);
+        let result = result_receiver.await.map_err(|_| QueryError::Dropped);
+        cancel_guard.disarm();
+        result
+    }
+
+    /// Pick a worker having `start_block` and execute the query on it. If the worker times out,
+    /// fails or has no allocation, retry on a different worker until the timeout is exceeded.
+    /// Returns `None` if no worker could be found at all.
+    pub async fn execute_routed_query(
+        &self,
+        request_id: Option<String>,
+        dataset_id: DatasetId,
+        query: String,
+        start_block: u32,
+        timeout: Option<impl Into<Duration>>,
+        profiling: bool,
+    ) -> Result<Option<RoutedQueryResult>, QueryError> {
+        let timeout = timeout
+            .map(Into::into)
+            .unwrap_or(Config::get().default_query_timeout);
+        let deadline = Instant::now() + timeout;
+        let mut tried_workers = Vec::new();
+        let mut last_attempt = None;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let worker_id = match self.network_state.read().await.find_worker_except(
+                &dataset_id,
+                start_block,
+                &tried_workers,
+            ) {
+                Some(worker_id) => worker_id,
+                None => break,
+            };
+            tried_workers.push(worker_id);
+
+            let query_id = QueryId::generate(request_id.clone());
+            let QueryResponse { result, exec_time } = self
+                .execute_query(
+                    query_id.clone(),
+                    dataset_id.clone(),
+                    query.clone(),
+                    worker_id,
+                    Some(remaining),
+                    profiling,
+                )
+                .await?;
+            match result {
+                QueryResult::Timeout(_)
+                | QueryResult::ServerError(_)
+                | QueryResult::NoAllocation => {
+                    log::debug!(
+                        "Query {query_id} to worker {worker_id} failed: {result}. Retrying"
+                    );
+                    last_attempt = Some((query_id, result, exec_time));
+                }
+                result => {
+                    return Ok(Some(RoutedQueryResult {
+                        query_id,
+                        result,
+                        exec_time,
+                        tried_workers,
+                    }))
+                }
+            }
+        }
+
+        Ok(
+            last_attempt.map(|(query_id, result, exec_time)| RoutedQueryResult {
+                query_id,
+                result,
+                exec_time,
+                tried_workers,
+            }),
+        )
     }
 }
 
@@ -101,6 +302,7 @@ pub async fn get_client<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static>
     allocations_db_path: PathBuf,
 ) -> anyhow::Result<QueryClient> {
     let (query_sender, query_receiver) = mpsc::channel(1000);
+    let (cancel_sender, cancel_receiver) = mpsc::channel(1000);
 
     let allocations_manager = Arc::new(RwLock::new(
         AllocationsManager::new(allocations_db_path).await?,
@@ -119,10 +321,17 @@ pub async fn get_client<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static>
         incoming_messages,
         transport_handle,
         query_receiver,
+        cancel_receiver,
         network_state.clone(),
         allocations_manager,
     );
 
-    let client = QueryClient::new(network_state, query_sender, chain_updates_handler, server);
+    let client = QueryClient::new(
+        network_state,
+        query_sender,
+        cancel_sender,
+        chain_updates_handler,
+        server,
+    );
     Ok(client)
 }
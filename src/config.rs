@@ -3,10 +3,12 @@ use std::fmt::{Display, Formatter};
 use std::path::Path;
 use std::time::Duration;
 
+use anyhow::Context;
 use base64::Engine;
 use base64::prelude::BASE64_URL_SAFE_NO_PAD;
 use serde::{Deserialize, Serialize};
 use serde_with::{DurationSeconds, serde_as};
+use subtle::ConstantTimeEq;
 use tokio::sync::OnceCell;
 
 use subsquid_network_transport::{ClientConfig, PeerId};
@@ -25,6 +27,10 @@ fn default_query_timeout() -> Duration {
     Duration::from_secs(60)
 }
 
+fn default_max_long_poll_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
 fn default_summary_print_interval() -> Duration {
     Duration::from_secs(30)
 }
@@ -33,6 +39,66 @@ fn default_workers_update_interval() -> Duration {
     Duration::from_secs(180)
 }
 
+fn default_exec_plans_max_size_mb() -> usize {
+    100
+}
+
+fn default_exec_plans_ttl() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_min_ready_workers() -> usize {
+    1
+}
+
+fn default_batch_max_queries() -> usize {
+    1000
+}
+
+fn default_batch_max_concurrency() -> usize {
+    20
+}
+
+fn default_batch_max_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_worker_url_ttl() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_exploration_rate() -> f64 {
+    0.1
+}
+
+fn default_latency_ewma_alpha() -> f64 {
+    0.3
+}
+
+fn default_balance_allocations() -> bool {
+    true
+}
+
+fn default_max_open_time() -> Duration {
+    Duration::from_secs(6 * 3600)
+}
+
+fn default_backoff_factor() -> f64 {
+    2.0
+}
+
+fn default_half_open_trials() -> u32 {
+    3
+}
+
+fn default_zstd_level() -> i32 {
+    3
+}
+
+fn default_brotli_level() -> u32 {
+    4
+}
+
 /// This struct exists not to confuse dataset name with it's encoded ID
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DatasetId(pub String);
@@ -47,6 +113,344 @@ impl DatasetId {
     pub fn from_url(url: impl AsRef<[u8]>) -> Self {
         Self(BASE64_URL_SAFE_NO_PAD.encode(url))
     }
+
+    pub fn to_url(&self) -> Option<String> {
+        let url = BASE64_URL_SAFE_NO_PAD.decode(&self.0).ok()?;
+        String::from_utf8(url).ok()
+    }
+}
+
+/// Group of HTTP routes an API key can be granted access to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Finding workers and executing queries
+    Query,
+    /// Read-only network state and metrics
+    State,
+    /// Managing the gateway
+    Admin,
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Query => write!(f, "query"),
+            Scope::State => write!(f, "state"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// What to do with queries sent to a specific worker which shouldn't get them,
+/// e.g. because it's greylisted or doesn't have the data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerCheckPolicy {
+    /// Return an error without sending the query
+    Reject,
+    /// Send the query anyway and log a warning
+    #[default]
+    Warn,
+    /// Send the query to another worker having the data
+    Reroute,
+}
+
+impl Display for WorkerCheckPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkerCheckPolicy::Reject => write!(f, "reject"),
+            WorkerCheckPolicy::Warn => write!(f, "warn"),
+            WorkerCheckPolicy::Reroute => write!(f, "reroute"),
+        }
+    }
+}
+
+/// How a worker is chosen among the ones having the requested block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerSelectionStrategy {
+    /// Any worker with the same probability
+    Random,
+    /// The faster of two random workers, based on the observed execution times
+    #[default]
+    Latency,
+}
+
+/// How the length of the workers' ranges starting at the requested block is taken into account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RangePolicy {
+    Ignore,
+    /// Pick workers with longer ranges more often
+    #[default]
+    Prefer,
+    /// Only pick among the workers whose ranges go furthest
+    Require,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct WorkerSelectionConfig {
+    #[serde(default)]
+    pub strategy: WorkerSelectionStrategy,
+    /// Share of queries sent to a random worker regardless of the strategy,
+    /// so that slow workers get a chance to be measured again. Between 0 and 1.
+    #[serde(default = "default_exploration_rate")]
+    pub exploration_rate: f64,
+    /// Weight of the newest execution time in the moving average, between 0 and 1
+    #[serde(default = "default_latency_ewma_alpha")]
+    pub latency_ewma_alpha: f64,
+    /// Pick workers having more compute units left more often,
+    /// so that all allocations run out at about the same time
+    #[serde(default = "default_balance_allocations")]
+    pub balance_allocations: bool,
+    /// Queries over this limit wait until the worker finishes one of its queries.
+    /// Other workers are chosen for new queries in the meantime.
+    #[serde(default)]
+    pub max_in_flight_per_worker: Option<usize>,
+    #[serde(default)]
+    pub range_policy: RangePolicy,
+}
+
+impl WorkerSelectionConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&self.exploration_rate),
+            "worker_selection.exploration_rate must be between 0 and 1"
+        );
+        anyhow::ensure!(
+            self.latency_ewma_alpha > 0.0 && self.latency_ewma_alpha <= 1.0,
+            "worker_selection.latency_ewma_alpha must be greater than 0 and at most 1"
+        );
+        Ok(())
+    }
+}
+
+impl Default for WorkerSelectionConfig {
+    fn default() -> Self {
+        Self {
+            strategy: Default::default(),
+            exploration_rate: default_exploration_rate(),
+            latency_ewma_alpha: default_latency_ewma_alpha(),
+            balance_allocations: default_balance_allocations(),
+            max_in_flight_per_worker: None,
+            range_policy: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    /// Key identifier, safe to appear in logs and metrics
+    pub id: String,
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Subject of the client certificate identifying the same client when mTLS is enabled,
+    /// e.g. `CN=indexer, O=Example`
+    #[serde(default)]
+    pub client_cert_subject: Option<String>,
+    pub scopes: Vec<Scope>,
+    /// Overrides the default rate limit for this key
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RateLimit {
+    /// Sustained number of requests per second
+    pub requests_per_sec: f64,
+    /// Maximum number of requests accepted at once after a period of inactivity
+    pub burst: u32,
+    /// Maximum number of requests being processed at the same time
+    #[serde(default)]
+    pub max_in_flight: Option<u32>,
+}
+
+impl RateLimit {
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.requests_per_sec.is_finite() && self.requests_per_sec > 0.0,
+            "rate_limit.requests_per_sec must be a positive number"
+        );
+        anyhow::ensure!(self.burst >= 1, "rate_limit.burst must be at least 1");
+        anyhow::ensure!(
+            self.max_in_flight != Some(0),
+            "rate_limit.max_in_flight must be at least 1"
+        );
+        Ok(())
+    }
+}
+
+/// Conditions for the gateway to report being ready to serve queries
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReadinessConfig {
+    /// Minimum number of workers which have recently sent pings
+    #[serde(default = "default_min_ready_workers")]
+    pub min_workers: usize,
+    /// Names of datasets which must have some data available
+    #[serde(default)]
+    pub critical_datasets: Vec<String>,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        Self {
+            min_workers: default_min_ready_workers(),
+            critical_datasets: Vec::new(),
+        }
+    }
+}
+
+/// Limits of the batch query endpoint
+#[serde_as]
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct BatchConfig {
+    /// Maximum number of queries in one batch
+    #[serde(default = "default_batch_max_queries")]
+    pub max_queries: usize,
+    /// Maximum number of queries of one batch running at the same time
+    #[serde(default = "default_batch_max_concurrency")]
+    pub max_concurrency: usize,
+    /// Maximum deadline a client can set for the whole batch
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "max_timeout_sec", default = "default_batch_max_timeout")]
+    pub max_timeout: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_queries: default_batch_max_queries(),
+            max_concurrency: default_batch_max_concurrency(),
+            max_timeout: default_batch_max_timeout(),
+        }
+    }
+}
+
+/// Signing of the URLs returned by the `get_worker` endpoint
+#[serde_as]
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct WorkerUrlsConfig {
+    /// How long a signed URL stays valid
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "ttl_sec", default = "default_worker_url_ttl")]
+    pub ttl: Duration,
+    /// Accept queries to URLs without a token, e.g. built by clients by hand.
+    /// Meant for the migration of clients. Required if no URL secret is configured.
+    #[serde(default)]
+    pub allow_unsigned: bool,
+}
+
+impl Default for WorkerUrlsConfig {
+    fn default() -> Self {
+        Self {
+            ttl: default_worker_url_ttl(),
+            allow_unsigned: false,
+        }
+    }
+}
+
+/// Greylisting of workers after timeouts and server errors
+#[serde_as]
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// How long a worker is greylisted after the first failure.
+    /// Set to `worker_greylist_time` when the config is read, if missing.
+    #[serde_as(as = "Option<DurationSeconds>")]
+    #[serde(rename = "initial_open_sec", default)]
+    pub initial_open_time: Option<Duration>,
+    /// Limit of the greylisting time. Failures after working for that long
+    /// start from `initial_open_sec` again.
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "max_open_sec", default = "default_max_open_time")]
+    pub max_open_time: Duration,
+    /// The greylisting time is multiplied by this after every failure in a row
+    #[serde(default = "default_backoff_factor")]
+    pub backoff_factor: f64,
+    /// Number of trial queries sent at a time after greylisting, which have to succeed
+    /// for the worker to be restored
+    #[serde(default = "default_half_open_trials")]
+    pub half_open_trials: u32,
+}
+
+impl CircuitBreakerConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.backoff_factor.is_finite() && self.backoff_factor >= 1.0,
+            "circuit_breaker.backoff_factor must be a finite number of at least 1"
+        );
+        anyhow::ensure!(
+            self.half_open_trials >= 1,
+            "circuit_breaker.half_open_trials must be at least 1"
+        );
+        Ok(())
+    }
+
+    /// Greylisting time after `opens` failures in a row
+    pub fn open_time(&self, opens: u32) -> Duration {
+        let initial_open_time = self.initial_open_time.unwrap_or_default();
+        // Multiplying zero by an overflowing factor would give NaN
+        if initial_open_time.is_zero() {
+            return Duration::ZERO;
+        }
+        let secs = initial_open_time.as_secs_f64()
+            * self
+                .backoff_factor
+                .powi(opens.try_into().unwrap_or(i32::MAX));
+        Duration::from_secs_f64(secs.min(self.max_open_time.as_secs_f64()))
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            initial_open_time: None,
+            max_open_time: default_max_open_time(),
+            backoff_factor: default_backoff_factor(),
+            half_open_trials: default_half_open_trials(),
+        }
+    }
+}
+
+/// Levels used when the worker's gzip payload is transcoded to the encoding requested by a client
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct CompressionLevels {
+    /// 1-22, higher levels above 19 need a lot of memory
+    #[serde(default = "default_zstd_level")]
+    pub zstd: i32,
+    /// 0-11
+    #[serde(default = "default_brotli_level")]
+    pub brotli: u32,
+}
+
+impl Default for CompressionLevels {
+    fn default() -> Self {
+        Self {
+            zstd: default_zstd_level(),
+            brotli: default_brotli_level(),
+        }
+    }
+}
+
+/// Limits of the in-memory store of exec plans collected with `profiling=true`
+#[serde_as]
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ExecPlansConfig {
+    /// Total size of stored (gzipped) plans. The oldest plans are evicted first.
+    #[serde(default = "default_exec_plans_max_size_mb")]
+    pub max_size_mb: usize,
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "ttl_sec", default = "default_exec_plans_ttl")]
+    pub ttl: Duration,
+}
+
+impl Default for ExecPlansConfig {
+    fn default() -> Self {
+        Self {
+            max_size_mb: default_exec_plans_max_size_mb(),
+            ttl: default_exec_plans_ttl(),
+        }
+    }
 }
 
 #[serde_as]
@@ -60,6 +464,8 @@ pub struct Config {
         default = "default_worker_inactive_threshold"
     )]
     pub worker_inactive_threshold: Duration,
+    /// Default duration of manual greylisting, and of automatic greylisting
+    /// after the first failure unless `circuit_breaker.initial_open_sec` is set
     #[serde_as(as = "DurationSeconds")]
     #[serde(
         rename = "worker_greylist_time_sec",
@@ -72,6 +478,13 @@ pub struct Config {
         default = "default_query_timeout"
     )]
     pub default_query_timeout: Duration,
+    /// Maximum time a height request with `wait_for` can be held
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(
+        rename = "max_long_poll_timeout_sec",
+        default = "default_max_long_poll_timeout"
+    )]
+    pub max_long_poll_timeout: Duration,
     #[serde_as(as = "DurationSeconds")]
     #[serde(
         rename = "summary_print_interval_sec",
@@ -87,12 +500,51 @@ pub struct Config {
     pub available_datasets: HashMap<String, DatasetId>,
     #[serde(default)]
     pub query_config: ClientConfig,
+    /// If empty, all routes are accessible without authentication
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    /// Default per-client rate limit. Clients are identified by API key or IP address.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub compression: CompressionLevels,
+    #[serde(default)]
+    pub exec_plans: ExecPlansConfig,
+    #[serde(default)]
+    pub readiness: ReadinessConfig,
+    #[serde(default)]
+    pub batch: BatchConfig,
+    /// Applies to queries sent to `/query/<dataset_id>/<worker_id>`
+    #[serde(default)]
+    pub worker_check_policy: WorkerCheckPolicy,
+    #[serde(default)]
+    pub worker_urls: WorkerUrlsConfig,
+    #[serde(default)]
+    pub worker_selection: WorkerSelectionConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
 }
 
 impl Config {
     pub async fn read(config_path: impl AsRef<Path>) -> anyhow::Result<()> {
         let file_contents = tokio::fs::read(config_path).await?;
-        let config = serde_yaml::from_slice(file_contents.as_slice())?;
+        let mut config: Self = serde_yaml::from_slice(file_contents.as_slice())?;
+        config.worker_selection.validate()?;
+        config.circuit_breaker.validate()?;
+        if let Some(rate_limit) = &config.rate_limit {
+            rate_limit.validate()?;
+        }
+        for api_key in &config.api_keys {
+            if let Some(rate_limit) = &api_key.rate_limit {
+                rate_limit
+                    .validate()
+                    .with_context(|| format!("Invalid rate limit of API key {}", api_key.id))?;
+            }
+        }
+        config
+            .circuit_breaker
+            .initial_open_time
+            .get_or_insert(config.worker_greylist_time);
         CONFIG.set(config)?;
         Ok(())
     }
@@ -105,4 +557,33 @@ impl Config {
     pub fn dataset_id(&self, dataset: &str) -> Option<DatasetId> {
         self.available_datasets.get(dataset).cloned()
     }
+
+    pub fn dataset_name(&self, dataset_id: &DatasetId) -> Option<&str> {
+        self.available_datasets
+            .iter()
+            .find_map(|(name, id)| (id == dataset_id).then_some(name.as_str()))
+    }
+
+    /// Keys are compared in constant time, so that response times don't reveal them
+    pub fn api_key(&self, key: &str) -> Option<&ApiKey> {
+        self.api_keys.iter().find(|api_key| {
+            api_key
+                .key
+                .as_ref()
+                .is_some_and(|configured| configured.as_bytes().ct_eq(key.as_bytes()).into())
+        })
+    }
+
+    pub fn api_key_by_cert(&self, subject: &str) -> Option<&ApiKey> {
+        self.api_keys
+            .iter()
+            .find(|api_key| api_key.client_cert_subject.as_deref() == Some(subject))
+    }
+
+    pub fn rate_limit(&self, key_id: Option<&str>) -> Option<RateLimit> {
+        key_id
+            .and_then(|id| self.api_keys.iter().find(|api_key| api_key.id == id))
+            .and_then(|api_key| api_key.rate_limit)
+            .or(self.rate_limit)
+    }
 }
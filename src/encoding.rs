@@ -0,0 +1,242 @@
+use std::io::Write;
+
+use axum::http::header::ACCEPT_ENCODING;
+use axum::http::HeaderMap;
+use flate2::write::GzDecoder;
+
+use crate::config::CompressionLevels;
+
+/// Content encoding of query results sent to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+    Brotli,
+    Identity,
+}
+
+impl Encoding {
+    /// Used to break ties between encodings accepted with the same quality.
+    /// Gzip goes first because workers already send gzipped results.
+    const PREFERENCE: [Encoding; 4] = [
+        Encoding::Gzip,
+        Encoding::Zstd,
+        Encoding::Brotli,
+        Encoding::Identity,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+            Encoding::Brotli => "br",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    fn matches(&self, coding: &str) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str())
+            || (*self == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+    }
+
+    /// Picks the encoding with the highest quality value in the `Accept-Encoding` headers.
+    /// Returns `None` if the client accepts none of the supported encodings, including identity.
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let accepted = accepted_codings(headers);
+        let mut best = None;
+        let mut best_q = 0.0;
+        for encoding in Self::PREFERENCE {
+            let q = encoding.quality(&accepted);
+            if q > best_q {
+                best = Some(encoding);
+                best_q = q;
+            }
+        }
+        best
+    }
+
+    /// Whether the client accepts uncompressed responses, which is the only option
+    /// for streamed responses
+    pub fn identity_accepted(headers: &HeaderMap) -> bool {
+        Encoding::Identity.quality(&accepted_codings(headers)) > 0.0
+    }
+
+    fn quality(&self, accepted: &[(&str, f32)]) -> f32 {
+        let explicit = accepted.iter().find(|(coding, _)| self.matches(coding));
+        let wildcard = accepted.iter().find(|(coding, _)| *coding == "*");
+        match explicit.or(wildcard) {
+            Some((_, q)) => *q,
+            // Identity is always acceptable unless explicitly excluded
+            None if *self == Encoding::Identity => 1.0,
+            None => 0.0,
+        }
+    }
+}
+
+fn accepted_codings(headers: &HeaderMap) -> Vec<(&str, f32)> {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(parse_coding)
+        .collect()
+}
+
+/// Parses `coding;q=0.5` into the coding and its quality value
+fn parse_coding(item: &str) -> Option<(&str, f32)> {
+    let mut parts = item.split(';').map(str::trim);
+    let coding = parts.next().filter(|coding| !coding.is_empty())?;
+    let q = match parts.find_map(|param| {
+        param
+            .strip_prefix("q=")
+            .or_else(|| param.strip_prefix("Q="))
+    }) {
+        Some(q) => q.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))?,
+        None => 1.0,
+    };
+    Some((coding, q))
+}
+
+/// Converts a gzipped worker response into the given encoding
+pub fn transcode(
+    data: Vec<u8>,
+    encoding: Encoding,
+    levels: CompressionLevels,
+) -> anyhow::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => Ok(data),
+        Encoding::Identity => decode_gzip(data),
+        Encoding::Zstd => {
+            let decoder = flate2::read::GzDecoder::new(data.as_slice());
+            let mut result = Vec::new();
+            zstd::stream::copy_encode(decoder, &mut result, levels.zstd)?;
+            Ok(result)
+        }
+        Encoding::Brotli => {
+            let mut decoder = flate2::read::GzDecoder::new(data.as_slice());
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, levels.brotli, 22);
+            std::io::copy(&mut decoder, &mut encoder)?;
+            encoder.flush()?;
+            Ok(encoder.into_inner())
+        }
+    }
+}
+
+/// Size of the decompressed data, read from the gzip trailer (modulo 2^32)
+pub fn gzip_decompressed_size(data: &[u8]) -> Option<u32> {
+    let trailer = data.len().checked_sub(4).map(|start| &data[start..])?;
+    Some(u32::from_le_bytes(trailer.try_into().ok()?))
+}
+
+pub fn decode_gzip(data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let buffer = Vec::new();
+    let mut decoder = GzDecoder::new(buffer);
+    decoder.write_all(data.as_slice())?;
+    Ok(decoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use axum::http::HeaderValue;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    fn headers(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn negotiate(values: &[&'static str]) -> Option<Encoding> {
+        Encoding::negotiate(&headers(values))
+    }
+
+    #[test]
+    fn parses_quality_values() {
+        assert_eq!(parse_coding("gzip"), Some(("gzip", 1.0)));
+        assert_eq!(parse_coding(" br ; q=0.5"), Some(("br", 0.5)));
+        assert_eq!(parse_coding("zstd;Q=0"), Some(("zstd", 0.0)));
+        assert_eq!(parse_coding("gzip;q=2"), None);
+        assert_eq!(parse_coding("gzip;q=abc"), None);
+        assert_eq!(parse_coding(""), None);
+    }
+
+    #[test]
+    fn highest_quality_wins() {
+        assert_eq!(negotiate(&[]), Some(Encoding::Identity));
+        assert_eq!(negotiate(&["br"]), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&["gzip;q=0.5, zstd"]), Some(Encoding::Zstd));
+        assert_eq!(
+            negotiate(&["gzip;q=0.5", "br;q=0.8, identity;q=0.1"]),
+            Some(Encoding::Brotli)
+        );
+        // Identity is acceptable with the highest quality unless listed
+        assert_eq!(negotiate(&["gzip;q=0.5"]), Some(Encoding::Identity));
+        assert_eq!(negotiate(&["X-GZIP"]), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&["deflate"]), Some(Encoding::Identity));
+    }
+
+    #[test]
+    fn ties_broken_by_preference() {
+        assert_eq!(negotiate(&["br, zstd, gzip"]), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&["br, zstd"]), Some(Encoding::Zstd));
+        assert_eq!(negotiate(&["*"]), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn zero_quality_excludes() {
+        assert_eq!(negotiate(&["gzip;q=0"]), Some(Encoding::Identity));
+        assert_eq!(negotiate(&["*, gzip;q=0"]), Some(Encoding::Zstd));
+        assert_eq!(negotiate(&["identity;q=0, br"]), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&["identity;q=0"]), None);
+        assert_eq!(negotiate(&["*;q=0"]), None);
+        assert_eq!(negotiate(&["*;q=0, identity"]), Some(Encoding::Identity));
+    }
+
+    #[test]
+    fn identity_acceptance() {
+        assert!(Encoding::identity_accepted(&headers(&[])));
+        assert!(Encoding::identity_accepted(&headers(&["gzip"])));
+        assert!(!Encoding::identity_accepted(&headers(&[
+            "gzip",
+            "identity;q=0"
+        ])));
+        assert!(!Encoding::identity_accepted(&headers(&["*;q=0"])));
+    }
+
+    #[test]
+    fn transcodes_gzip() {
+        let data = b"[{\"header\":{\"number\":1}}]".repeat(100);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let levels = CompressionLevels::default();
+
+        assert_eq!(gzip_decompressed_size(&gzipped), Some(data.len() as u32));
+        assert_eq!(
+            transcode(gzipped.clone(), Encoding::Gzip, levels).unwrap(),
+            gzipped
+        );
+        assert_eq!(
+            transcode(gzipped.clone(), Encoding::Identity, levels).unwrap(),
+            data
+        );
+
+        let zstd = transcode(gzipped.clone(), Encoding::Zstd, levels).unwrap();
+        assert_eq!(zstd::decode_all(zstd.as_slice()).unwrap(), data);
+
+        let brotli = transcode(gzipped, Encoding::Brotli, levels).unwrap();
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(brotli.as_slice(), 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::ExecPlansConfig;
+
+struct StoredPlan {
+    /// Gzipped JSON, as received from the worker
+    data: Vec<u8>,
+    saved_at: Instant,
+}
+
+#[derive(Default)]
+struct Plans {
+    by_query: HashMap<String, StoredPlan>,
+    /// Query IDs from the oldest to the newest plan
+    order: VecDeque<String>,
+    total_size: usize,
+}
+
+impl Plans {
+    fn remove_oldest(&mut self) {
+        if let Some(query_id) = self.order.pop_front() {
+            if let Some(plan) = self.by_query.remove(&query_id) {
+                self.total_size -= plan.data.len();
+            }
+        }
+    }
+
+    fn oldest_saved_at(&self) -> Option<Instant> {
+        self.order
+            .front()
+            .and_then(|query_id| self.by_query.get(query_id))
+            .map(|plan| plan.saved_at)
+    }
+}
+
+/// Execution plans of profiled queries, kept in memory until they expire
+/// or get evicted by newer plans when the size limit is reached
+pub struct ExecPlanStore {
+    max_size: usize,
+    ttl: Duration,
+    plans: Mutex<Plans>,
+}
+
+impl ExecPlanStore {
+    pub fn new(config: &ExecPlansConfig) -> Self {
+        Self {
+            max_size: config.max_size_mb * 1024 * 1024,
+            ttl: config.ttl,
+            plans: Default::default(),
+        }
+    }
+
+    pub fn save(&self, query_id: String, data: Vec<u8>) {
+        if data.len() > self.max_size {
+            return log::warn!(
+                "Exec plan for query {query_id} is too large to be stored: {} bytes",
+                data.len()
+            );
+        }
+        let mut plans = self.plans.lock().unwrap();
+        if plans.by_query.contains_key(&query_id) {
+            return log::warn!("Exec plan for query {query_id} already stored");
+        }
+        self.remove_expired(&mut plans);
+        while plans.total_size + data.len() > self.max_size {
+            plans.remove_oldest();
+        }
+        plans.total_size += data.len();
+        plans.order.push_back(query_id.clone());
+        let plan = StoredPlan {
+            data,
+            saved_at: Instant::now(),
+        };
+        plans.by_query.insert(query_id, plan);
+    }
+
+    /// Returns the gzipped plan
+    pub fn get(&self, query_id: &str) -> Option<Vec<u8>> {
+        let mut plans = self.plans.lock().unwrap();
+        self.remove_expired(&mut plans);
+        plans.by_query.get(query_id).map(|plan| plan.data.clone())
+    }
+
+    fn remove_expired(&self, plans: &mut Plans) {
+        while plans
+            .oldest_saved_at()
+            .is_some_and(|saved_at| saved_at.elapsed() > self.ttl)
+        {
+            plans.remove_oldest();
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::config::Config;
+use crate::network_state::NetworkState;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct HealthCheck {
+    name: String,
+    ok: bool,
+    message: String,
+}
+
+impl HealthCheck {
+    pub fn new(name: impl ToString, ok: bool, message: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            ok,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct HealthStatus {
+    /// True if all the checks have passed
+    ok: bool,
+    checks: Vec<HealthCheck>,
+}
+
+impl HealthStatus {
+    pub fn new(checks: Vec<HealthCheck>) -> Self {
+        Self {
+            ok: checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+
+    pub fn ok(&self) -> bool {
+        self.ok
+    }
+}
+
+/// The gateway is ready once it knows the workers from the chain,
+/// enough of them are sending pings and the critical datasets have data
+pub fn readiness(network_state: &NetworkState) -> HealthStatus {
+    let config = &Config::get().readiness;
+    let mut checks = Vec::new();
+
+    checks.push(match network_state.last_chain_update() {
+        Some(time) => HealthCheck::new(
+            "chain_update",
+            true,
+            format!(
+                "Last update at {}",
+                DateTime::<Utc>::from(time).to_rfc3339()
+            ),
+        ),
+        None => HealthCheck::new("chain_update", false, "No successful update yet"),
+    });
+
+    let active_workers = network_state.active_workers_count();
+    checks.push(HealthCheck::new(
+        "workers",
+        active_workers >= config.min_workers,
+        format!(
+            "{active_workers} workers sending pings, at least {} required",
+            config.min_workers
+        ),
+    ));
+
+    for dataset in &config.critical_datasets {
+        let name = format!("dataset:{dataset}");
+        let check = match Config::get().dataset_id(dataset) {
+            None => HealthCheck::new(name, false, "Unknown dataset"),
+            Some(dataset_id) => {
+                let height = network_state.get_height(&dataset_id).unwrap_or(0);
+                HealthCheck::new(
+                    name,
+                    height > 0,
+                    format!("Highest indexable block: {height}"),
+                )
+            }
+        };
+        checks.push(check);
+    }
+
+    HealthStatus::new(checks)
+}
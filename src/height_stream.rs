@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::response::sse::Event;
+use futures::{Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::RwLock;
+
+use crate::config::DatasetId;
+use crate::network_state::{HeightUpdate, NetworkState};
+
+/// Emits the current heights of the given datasets and then every change of them
+pub async fn height_events(
+    network_state: Arc<RwLock<NetworkState>>,
+    datasets: BTreeMap<String, DatasetId>,
+) -> impl Stream<Item = Result<Event, axum::Error>> {
+    // Updates are sent under the write lock, so none can be missed between
+    // reading the current heights and subscribing
+    let (receiver, current) = {
+        let state = network_state.read().await;
+        (
+            state.subscribe_heights(),
+            current_heights(&state, &datasets),
+        )
+    };
+
+    let updates = futures::stream::unfold(
+        (receiver, network_state, datasets),
+        |(mut receiver, network_state, datasets): (Receiver<HeightUpdate>, _, _)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(update) if datasets.contains_key(&update.dataset) => {
+                        return Some((vec![update], (receiver, network_state, datasets)));
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Height stream lagged behind by {skipped} updates");
+                        let current = current_heights(&*network_state.read().await, &datasets);
+                        return Some((current, (receiver, network_state, datasets)));
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+
+    futures::stream::iter(current)
+        .chain(updates.flat_map(futures::stream::iter))
+        .map(|update| Event::default().event("height").json_data(update))
+}
+
+fn current_heights(
+    state: &NetworkState,
+    datasets: &BTreeMap<String, DatasetId>,
+) -> Vec<HeightUpdate> {
+    datasets
+        .iter()
+        .map(|(dataset, dataset_id)| HeightUpdate {
+            dataset: dataset.clone(),
+            height: state.dataset_height(dataset_id),
+        })
+        .collect()
+}
@@ -1,190 +1,1344 @@
-use std::fmt::Display;
-use std::io::Write;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use axum::extract::{Extension, Host, Path, Query};
-use axum::http::{HeaderMap, StatusCode};
+use axum::body::Body;
+use axum::extract::{Extension, Host, Request};
+use axum::handler::Handler;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::routing::{get, post};
+use axum::routing::{on, MethodFilter, MethodRouter};
 use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
 use duration_string::DurationString;
-use flate2::write::GzDecoder;
 use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::{Map, Value};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::RwLock;
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
 use subsquid_messages::OkResult;
 use subsquid_network_transport::PeerId;
 
-use crate::client::QueryClient;
-use crate::config::{Config, DatasetId};
+use crate::api_error::{self, ApiError, ErrorCode, JsonBody, Path, Query};
+use crate::auth::{self, ClientId};
+use crate::batch::{Batch, BatchItem};
+use crate::circuit_breaker::{BreakerEvent, BreakerState, BreakerSummary, Failure};
+use crate::client::{QueryClient, RoutedQueryResult};
+use crate::config::{Config, DatasetId, Scope, WorkerCheckPolicy};
+use crate::encoding::{self, Encoding};
+use crate::exec_plans::ExecPlanStore;
+use crate::health::{self, HealthCheck, HealthStatus};
+use crate::height_stream;
 use crate::metrics;
-use crate::network_state::NetworkState;
-use crate::query::QueryResult;
+use crate::network_state::{
+    DatasetHeight, DatasetInfo, HeightUpdate, NetworkState, WorkerInfo, WorkerProblem,
+};
+use crate::query::{QueryId, QueryRange, QueryResponse, QueryResult};
+use crate::range_stream::RangeStream;
+use crate::rate_limit::{self, ClientQuota, RateLimiter};
 use crate::scheme_extractor::Scheme;
+use crate::tls::{self, ClientCertAcceptor, TlsFiles};
+use crate::worker_overrides::{OverrideKind, WorkerOverride, WorkerOverridesStore};
+use crate::worker_stats::LatencySummary;
+use crate::worker_urls::{self, TokenError, TokenParams, UrlSigner, WorkerUrl};
 
+const TRIED_WORKERS_HEADER: &str = "x-sqd-tried-workers";
+pub const QUERY_ID_HEADER: &str = "x-query-id";
+pub const WORKER_ID_HEADER: &str = "x-worker-id";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const EXEC_TIME_HEADER: &str = "x-exec-time-ms";
+const COMPRESSED_SIZE_HEADER: &str = "x-compressed-size";
+const DECOMPRESSED_SIZE_HEADER: &str = "x-decompressed-size";
+const TARGET_REACHED_HEADER: &str = "x-target-reached";
+const WORKER_CHECK_HEADER: &str = "x-worker-check";
+const REROUTED_FROM_HEADER: &str = "x-rerouted-from";
+const RANGE_END_HEADER: &str = "x-range-end";
+const MAX_REQUEST_ID_LEN: usize = 128;
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(5);
+const OVERRIDES_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Subsquid query gateway"),
+    tags(
+        (name = "query", description = "Requires an API key with the `query` scope"),
+        (name = "state", description = "Requires an API key with the `state` scope"),
+        (name = "admin", description = "Requires an API key with the `admin` scope"),
+        (name = "public", description = "Never requires an API key"),
+    ),
+    paths(
+        get_height,
+        stream_heights,
+        get_worker,
+        execute_query,
+        execute_dataset_query,
+        stream_dataset_range,
+        execute_batch,
+        get_exec_plan,
+        list_datasets,
+        get_dataset,
+        get_network_state,
+        greylisted_workers,
+        worker_latencies,
+        get_worker_info,
+        get_metrics,
+        worker_overrides,
+        greylist_worker,
+        ban_worker,
+        restore_worker,
+        get_openapi,
+        liveness,
+        readiness,
+    ),
+    components(schemas(
+        ApiError,
+        ErrorCode,
+        DatasetInfo,
+        WorkerOverride,
+        OverrideKind,
+        OverrideParams,
+        HealthStatus,
+        HealthCheck,
+        HeightUpdate,
+        DatasetHeight,
+        BatchItem,
+        LatencySummary,
+        WorkerInfo,
+        BreakerSummary,
+        BreakerEvent,
+        BreakerState,
+        Failure
+    ))
+)]
+struct ApiDoc;
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HeightParams {
+    /// Wait until the height reaches this block
+    wait_for: Option<u32>,
+    /// Maximum waiting time, e.g. `30s`. Defaults to `default_query_timeout` and is capped
+    /// by `max_long_poll_timeout_sec` from the config.
+    #[param(value_type = Option<String>)]
+    timeout: Option<DurationString>,
+}
+
+/// Get the highest block available in the network for the dataset.
+/// With `wait_for`, the response is held until the height reaches the given block or the timeout expires.
+#[utoipa::path(
+    get,
+    path = "/network/{dataset}/height",
+    tag = "query",
+    params(("dataset" = String, Path, description = "Dataset name"), HeightParams),
+    responses(
+        (status = 200, description = "Block number", body = String, content_type = "text/plain",
+            headers(("x-target-reached" = bool, description = "Whether the `wait_for` block has been reached, only sent with `wait_for`"))),
+        (status = 404, description = "Unknown dataset", body = ApiError),
+        (status = 503, description = "No data for the dataset yet", body = ApiError),
+    )
+)]
 async fn get_height(
     Path(dataset): Path<String>,
+    Query(HeightParams { wait_for, timeout }): Query<HeightParams>,
     Extension(client): Extension<Arc<QueryClient>>,
-) -> impl IntoResponse {
+) -> Result<Response, ApiError> {
     log::debug!("Get height dataset={dataset}");
-    let dataset_id = match Config::get().dataset_id(&dataset) {
-        Some(dataset_id) => dataset_id,
-        None => return (StatusCode::NOT_FOUND, format!("Unknown dataset: {dataset}")),
-    };
+    let dataset_id = Config::get()
+        .dataset_id(&dataset)
+        .ok_or_else(|| ApiError::unknown_dataset(&dataset))?;
 
-    match client.get_height(&dataset_id).await {
-        Some(height) => (StatusCode::OK, height.to_string()),
-        None => (
-            StatusCode::SERVICE_UNAVAILABLE,
-            format!("No data for dataset {dataset}"),
-        ),
+    let height = match wait_for {
+        Some(target_block) => {
+            let timeout = timeout
+                .map(Into::into)
+                .unwrap_or(Config::get().default_query_timeout)
+                .min(Config::get().max_long_poll_timeout);
+            client
+                .wait_for_height(&dataset_id, target_block, timeout)
+                .await
+        }
+        None => client.get_height(&dataset_id).await,
+    };
+    let Some(height) = height else {
+        return Err(ApiError::no_data(&dataset));
+    };
+    let mut response = height.to_string().into_response();
+    if let Some(target_block) = wait_for {
+        let reached = if height >= target_block {
+            "true"
+        } else {
+            "false"
+        };
+        response
+            .headers_mut()
+            .insert(TARGET_REACHED_HEADER, HeaderValue::from_static(reached));
     }
+    Ok(response)
+}
+
+/// Stream the heights of the datasets as server-sent `height` events.
+/// The current heights are sent first, then every change of them.
+#[utoipa::path(
+    get,
+    path = "/datasets/{dataset}/height/stream",
+    tag = "query",
+    params(("dataset" = String, Path, description = "Comma-separated dataset names")),
+    responses(
+        (status = 200, description = "Height updates", body = HeightUpdate, content_type = "text/event-stream"),
+        (status = 404, description = "Unknown dataset", body = ApiError),
+    )
+)]
+async fn stream_heights(
+    Path(datasets): Path<String>,
+    Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
+) -> Result<Response, ApiError> {
+    log::debug!("Stream heights datasets={datasets}");
+    let datasets = datasets
+        .split(',')
+        .map(|dataset| {
+            Config::get()
+                .dataset_id(dataset)
+                .map(|dataset_id| (dataset.to_string(), dataset_id))
+                .ok_or_else(|| ApiError::unknown_dataset(dataset))
+        })
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+
+    let events = height_stream::height_events(network_state, datasets).await;
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
 }
 
+/// Get the URL of a worker which can process a query starting at the given block.
+/// The URL is signed and only valid for queries starting at this block until it expires.
+#[utoipa::path(
+    get,
+    path = "/network/{dataset}/{start_block}/worker",
+    tag = "query",
+    params(
+        ("dataset" = String, Path, description = "Dataset name"),
+        ("start_block" = u32, Path, description = "First block of the query"),
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Worker query URL",
+            body = String,
+            content_type = "text/plain",
+            headers(("x-range-end" = u32, description = "Last block the worker has without gaps starting at `start_block`")),
+        ),
+        (status = 404, description = "Unknown dataset", body = ApiError),
+        (status = 503, description = "No available worker", body = ApiError),
+    )
+)]
 async fn get_worker(
     Scheme(scheme): Scheme,
     Host(host): Host,
     Path((dataset, start_block)): Path<(String, u32)>,
     Extension(client): Extension<Arc<QueryClient>>,
-) -> impl IntoResponse {
+    Extension(url_signer): Extension<Arc<UrlSigner>>,
+) -> Result<Response, ApiError> {
     log::debug!("Get worker dataset={dataset} start_block={start_block}");
-    let dataset_id = match Config::get().dataset_id(&dataset) {
-        Some(dataset_id) => dataset_id,
-        None => return (StatusCode::NOT_FOUND, format!("Unknown dataset: {dataset}")),
-    };
+    let dataset_id = Config::get()
+        .dataset_id(&dataset)
+        .ok_or_else(|| ApiError::unknown_dataset(&dataset))?;
 
-    let worker_id = match client.find_worker(&dataset_id, start_block).await {
-        Some(worker_id) => worker_id,
-        None => {
-            return (
-                StatusCode::SERVICE_UNAVAILABLE,
-                format!("No available worker for dataset {dataset} block {start_block}"),
-            )
-        }
-    };
+    let worker_id = client
+        .find_worker(&dataset_id, start_block)
+        .await
+        .ok_or_else(|| ApiError::no_worker(&dataset, start_block))?;
 
-    (
-        StatusCode::OK,
-        format!("{scheme}://{host}/query/{dataset_id}/{worker_id}"),
-    )
+    let range_end = client
+        .worker_range_end(&dataset_id, &worker_id, start_block)
+        .await
+        .unwrap_or(start_block);
+
+    let ttl = Config::get().worker_urls.ttl;
+    let mut url = format!("{scheme}://{host}/query/{dataset_id}/{worker_id}");
+    if let Some(token) = url_signer.sign(&dataset_id, &worker_id, start_block, ttl) {
+        url = format!("{url}?token={token}");
+    }
+    let mut response = url.into_response();
+    response
+        .headers_mut()
+        .insert(RANGE_END_HEADER, HeaderValue::from(range_end));
+    Ok(response)
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct ExecuteParams {
+    /// Query timeout, e.g. `30s`. Defaults to `default_query_timeout` from the config.
+    #[param(value_type = Option<String>)]
     timeout: Option<DurationString>,
+    /// Ask the worker to collect an execution plan
     #[serde(default)]
     profiling: bool,
 }
 
+/// Execute a query on the given worker. The URL must be signed by the worker endpoint
+/// for the query's `fromBlock`. Workers which shouldn't get the query are handled
+/// according to `worker_check_policy`: the query is rejected, sent anyway, or sent to another worker.
+#[utoipa::path(
+    post,
+    path = "/query/{dataset_id}/{worker_id}",
+    tag = "query",
+    params(
+        ("dataset_id" = String, Path, description = "Encoded dataset ID"),
+        ("worker_id" = String, Path, description = "Peer ID of the worker"),
+        TokenParams,
+        ExecuteParams,
+        ("x-request-id" = Option<String>, Header, description = "Client's ID included in the gateway logs and returned in the response"),
+    ),
+    request_body(content = Object, description = "Archive query", content_type = "application/json"),
+    responses(
+        (
+            status = 200,
+            description = "Query result, compressed according to `Accept-Encoding`",
+            body = Object,
+            headers(
+                ("x-query-id" = String, description = "Query ID, also used to fetch the exec plan"),
+                ("x-worker-id" = String, description = "Peer ID of the worker which executed the query"),
+                ("x-exec-time-ms" = u64, description = "Execution time measured by the gateway"),
+                ("x-compressed-size" = u64, description = "Size of the gzipped result returned by the worker"),
+                ("x-decompressed-size" = u64, description = "Size of the decompressed result"),
+                ("x-worker-check" = String, description = "`ok`, or the reason why the requested worker shouldn't get the query"),
+                ("x-rerouted-from" = String, description = "Requested worker, if the query has been sent to another one"),
+            ),
+        ),
+        (status = 400, description = "Invalid query", body = ApiError),
+        (status = 403, description = "Missing, expired or invalid token", body = ApiError),
+        (status = 406, description = "No supported encoding is accepted", body = ApiError),
+        (status = 503, description = "The worker cannot process the query", body = ApiError),
+        (status = 504, description = "Query timed out", body = ApiError),
+    )
+)]
 async fn execute_query(
-    Path((dataset_id, worker_id)): Path<(DatasetId, PeerId)>,
+    WorkerUrl {
+        dataset_id,
+        worker_id: requested_worker,
+        token,
+    }: WorkerUrl,
     Query(ExecuteParams { timeout, profiling }): Query<ExecuteParams>,
     Extension(client): Extension<Arc<QueryClient>>,
+    Extension(exec_plans): Extension<Arc<ExecPlanStore>>,
+    client_id: Option<Extension<ClientId>>,
     headers: HeaderMap,
     query: String, // request body
-) -> Response {
-    log::debug!("Execute query dataset_id={dataset_id} worker_id={worker_id}");
-    match client
-        .execute_query(dataset_id, query, worker_id, timeout, profiling)
+) -> Result<Response, ApiError> {
+    let query_id = QueryId::generate(request_id(&headers)?);
+    log::debug!(
+        "Execute query {query_id} dataset_id={dataset_id} worker_id={requested_worker} client_id={}",
+        display_client(&client_id)
+    );
+    let encoding = Encoding::negotiate(&headers)
+        .ok_or_else(|| ApiError::not_acceptable().with_query_id(&query_id.id))?;
+    let start_block = QueryRange::parse(&query)
+        .map_err(|err| {
+            ApiError::bad_request(format!("Invalid query: {err}")).with_query_id(&query_id.id)
+        })?
+        .from_block;
+    check_token(token, &client, &dataset_id, &requested_worker, start_block)
         .await
-    {
-        Err(err) => server_error(err),
-        Ok(QueryResult::Ok(result)) => ok_response(result, headers),
-        Ok(res) => (res.status_code(), res.to_string()).into_response(),
+        .map_err(|err| err.with_query_id(&query_id.id))?;
+    let (problem, worker_id) = check_worker(
+        &client,
+        &query_id,
+        &dataset_id,
+        requested_worker,
+        start_block,
+    )
+    .await;
+    let rerouted = matches!(worker_id, Ok(worker_id) if worker_id != requested_worker);
+
+    let result = match worker_id {
+        Err(err) => Err(err),
+        Ok(worker_id) => match client
+            .execute_query(
+                query_id.clone(),
+                dataset_id,
+                query,
+                worker_id,
+                timeout,
+                profiling,
+            )
+            .await
+        {
+            Err(err) => Err(err.into()),
+            Ok(QueryResponse {
+                result: QueryResult::Ok(result),
+                exec_time,
+            }) => {
+                let plans = exec_plans.as_ref();
+                ok_response(&query_id, worker_id, exec_time, result, encoding, plans).await
+            }
+            Ok(QueryResponse { result, .. }) => Err(ApiError::from_result(&result)),
+        }
+        .map_err(|err| err.with_worker_id(worker_id)),
+    };
+    let mut response = result
+        .map_err(|err| err.with_query_id(&query_id.id))
+        .into_response();
+
+    let check = problem.map_or("ok", |problem| problem.as_str());
+    response
+        .headers_mut()
+        .insert(WORKER_CHECK_HEADER, HeaderValue::from_static(check));
+    if rerouted {
+        response.headers_mut().insert(
+            REROUTED_FROM_HEADER,
+            requested_worker.to_string().parse().unwrap(),
+        );
+    }
+    Ok(response)
+}
+
+/// Checks that the worker URL has been issued by the gateway, and the query's start block
+/// is in the range of the worker containing the block the URL has been issued for.
+/// Unsigned URLs pass if `worker_urls.allow_unsigned` is set.
+async fn check_token(
+    token: Result<u32, TokenError>,
+    client: &QueryClient,
+    dataset_id: &DatasetId,
+    worker_id: &PeerId,
+    start_block: u32,
+) -> Result<(), ApiError> {
+    let result = match token {
+        Ok(block) => {
+            // If the worker no longer has the range, the worker check deals with it
+            let range_end = client.worker_range_end(dataset_id, worker_id, block).await;
+            worker_urls::check_start_block(block, range_end, start_block)
+        }
+        Err(err) => Err(err),
+    };
+    metrics::worker_url_checked(result.map_or_else(|err| err.as_str(), |()| "ok"));
+    match result {
+        Err(TokenError::Missing) if Config::get().worker_urls.allow_unsigned => Ok(()),
+        Err(err) => {
+            log::debug!("Rejected query to worker {worker_id}: {err} token");
+            Err(ApiError::invalid_token(err))
+        }
+        Ok(()) => Ok(()),
     }
 }
 
-#[inline(always)]
-fn server_error(err: impl Display) -> Response {
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+/// Checks whether the worker chosen by the client should get the query.
+/// Unregistered and banned workers are always rejected, `worker_check_policy` applies to other problems.
+async fn check_worker(
+    client: &QueryClient,
+    query_id: &QueryId,
+    dataset_id: &DatasetId,
+    requested_worker: PeerId,
+    start_block: u32,
+) -> (Option<WorkerProblem>, Result<PeerId, ApiError>) {
+    let Some(problem) = client
+        .check_worker(&requested_worker, dataset_id, Some(start_block))
+        .await
+    else {
+        return (None, Ok(requested_worker));
+    };
+    let policy = if problem.is_hard() {
+        WorkerCheckPolicy::Reject
+    } else {
+        Config::get().worker_check_policy
+    };
+    metrics::worker_check_failed(problem.as_str(), &policy.to_string());
+    let worker_id = match policy {
+        WorkerCheckPolicy::Warn => {
+            log::warn!("Query {query_id} sent to worker {requested_worker}: {problem}");
+            Ok(requested_worker)
+        }
+        WorkerCheckPolicy::Reject => Err(ApiError::worker_unavailable(requested_worker, problem)),
+        WorkerCheckPolicy::Reroute => {
+            let worker_id = client.find_worker(dataset_id, start_block).await;
+            log::debug!(
+                "Query {query_id} for worker {requested_worker} ({problem}) rerouted to {worker_id:?}"
+            );
+            worker_id.ok_or_else(|| ApiError::worker_unavailable(requested_worker, problem))
+        }
+    };
+    (Some(problem), worker_id)
 }
 
-fn ok_response(result: OkResult, request_headers: HeaderMap) -> Response {
-    let OkResult {
-        mut data,
-        exec_plan, ..
-    } = result;
-    if let Some(exec_plan) = exec_plan {
-        save_exec_plan(exec_plan);
+/// Execute a query on any worker having the data, retrying on others if it fails
+#[utoipa::path(
+    post,
+    path = "/datasets/{dataset}/query",
+    tag = "query",
+    params(
+        ("dataset" = String, Path, description = "Dataset name"),
+        ExecuteParams,
+        ("x-request-id" = Option<String>, Header, description = "Client's ID included in the gateway logs and returned in the response"),
+    ),
+    request_body(content = Object, description = "Archive query", content_type = "application/json"),
+    responses(
+        (
+            status = 200,
+            description = "Query result, compressed according to `Accept-Encoding`",
+            body = Object,
+            headers(
+                ("x-query-id" = String, description = "ID of the last query sent, also used to fetch the exec plan"),
+                ("x-worker-id" = String, description = "Peer ID of the worker which executed the query"),
+                ("x-exec-time-ms" = u64, description = "Execution time measured by the gateway"),
+                ("x-compressed-size" = u64, description = "Size of the gzipped result returned by the worker"),
+                ("x-decompressed-size" = u64, description = "Size of the decompressed result"),
+                ("x-sqd-tried-workers" = String, description = "Comma-separated workers the query was sent to"),
+            ),
+        ),
+        (status = 400, description = "Invalid query", body = ApiError),
+        (status = 404, description = "Unknown dataset", body = ApiError),
+        (status = 406, description = "No supported encoding is accepted", body = ApiError),
+        (status = 503, description = "No available worker", body = ApiError),
+        (status = 504, description = "Query timed out", body = ApiError),
+    )
+)]
+async fn execute_dataset_query(
+    Path(dataset): Path<String>,
+    Query(ExecuteParams { timeout, profiling }): Query<ExecuteParams>,
+    Extension(client): Extension<Arc<QueryClient>>,
+    Extension(exec_plans): Extension<Arc<ExecPlanStore>>,
+    client_id: Option<Extension<ClientId>>,
+    headers: HeaderMap,
+    query: String, // request body
+) -> Result<Response, ApiError> {
+    let request_id = request_id(&headers)?;
+    log::debug!(
+        "Execute query dataset={dataset} client_id={} request_id={}",
+        display_client(&client_id),
+        request_id.as_deref().unwrap_or("-")
+    );
+    let encoding = Encoding::negotiate(&headers).ok_or_else(ApiError::not_acceptable)?;
+    let dataset_id = Config::get()
+        .dataset_id(&dataset)
+        .ok_or_else(|| ApiError::unknown_dataset(&dataset))?;
+    let start_block = QueryRange::parse(&query)
+        .map_err(|err| ApiError::bad_request(format!("Invalid query: {err}")))?
+        .from_block;
+
+    let RoutedQueryResult {
+        query_id,
+        result,
+        exec_time,
+        tried_workers,
+    } = client
+        .execute_routed_query(
+            request_id,
+            dataset_id,
+            query,
+            start_block,
+            timeout,
+            profiling,
+        )
+        .await?
+        .ok_or_else(|| ApiError::no_worker(&dataset, start_block))?;
+
+    let worker_id = *tried_workers.last().expect("Query was sent to a worker");
+    let mut response = match result {
+        QueryResult::Ok(result) => {
+            let plans = exec_plans.as_ref();
+            ok_response(&query_id, worker_id, exec_time, result, encoding, plans).await
+        }
+        res => Err(ApiError::from_result(&res)),
+    }
+    .map_err(|err| err.with_query_id(&query_id.id).with_worker_id(worker_id))
+    .into_response();
+    let tried_workers = tried_workers
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    if let Ok(value) = HeaderValue::from_str(&tried_workers) {
+        response.headers_mut().insert(TRIED_WORKERS_HEADER, value);
+    }
+    Ok(response)
+}
+
+/// Stream all blocks of the query range as newline-delimited JSON
+#[utoipa::path(
+    post,
+    path = "/datasets/{dataset}/stream",
+    tag = "query",
+    params(
+        ("dataset" = String, Path, description = "Dataset name"),
+        ExecuteParams,
+        ("x-request-id" = Option<String>, Header, description = "Client's ID included in the gateway logs and returned in the response"),
+    ),
+    request_body(content = Object, description = "Archive query", content_type = "application/json"),
+    responses(
+        (status = 200, description = "One block per line", body = String, content_type = "application/x-ndjson"),
+        (status = 400, description = "Invalid query", body = ApiError),
+        (status = 404, description = "Unknown dataset", body = ApiError),
+        (status = 406, description = "Uncompressed responses are not accepted", body = ApiError),
+        (status = 503, description = "Dataset height is not known yet", body = ApiError),
+    )
+)]
+async fn stream_dataset_range(
+    Path(dataset): Path<String>,
+    Query(ExecuteParams { timeout, profiling }): Query<ExecuteParams>,
+    Extension(client): Extension<Arc<QueryClient>>,
+    client_id: Option<Extension<ClientId>>,
+    headers: HeaderMap,
+    query: String, // request body
+) -> Result<Response, ApiError> {
+    let request_id = request_id(&headers)?;
+    log::debug!(
+        "Stream range dataset={dataset} client_id={} request_id={}",
+        display_client(&client_id),
+        request_id.as_deref().unwrap_or("-")
+    );
+    if !Encoding::identity_accepted(&headers) {
+        return Err(ApiError::not_acceptable());
+    }
+    let dataset_id = Config::get()
+        .dataset_id(&dataset)
+        .ok_or_else(|| ApiError::unknown_dataset(&dataset))?;
+    let (range, query) = QueryRange::parse(&query)
+        .and_then(|range| Ok((range, serde_json::from_str::<Map<String, Value>>(&query)?)))
+        .map_err(|err| ApiError::bad_request(format!("Invalid query: {err}")))?;
+    // Otherwise the stream would end right away, as if there were no blocks in the range
+    if client.get_height(&dataset_id).await.is_none() {
+        return Err(ApiError::no_data(&dataset));
     }
 
+    let stream = RangeStream::new(
+        client,
+        dataset_id,
+        query,
+        range.from_block,
+        range.to_block,
+        timeout.map(Into::into),
+        profiling,
+    )
+    .with_request_id(request_id)
+    .into_stream();
     let mut headers = HeaderMap::new();
-    headers.insert("content-type", "application/json".parse().unwrap());
+    headers.insert("content-type", "application/x-ndjson".parse().unwrap());
+    Ok((StatusCode::OK, headers, Body::from_stream(stream)).into_response())
+}
 
-    // If client accepts gzip compressed data, return it as-is. Otherwise, decompress.
-    let gzip_accepted = request_headers
-        .get_all("accept-encoding")
-        .iter()
-        .filter_map(|x| x.to_str().ok())
-        .any(|x| x.contains("gzip"));
-    if gzip_accepted {
-        headers.insert("content-encoding", "gzip".parse().unwrap());
-    } else {
-        data = match decode_gzip(data) {
-            Ok(data) => data,
-            Err(err) => return server_error(err),
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct BatchParams {
+    /// Deadline for the whole batch, e.g. `30s`. Defaults to `default_query_timeout` and is capped
+    /// by `batch.max_timeout_sec` from the config.
+    #[param(value_type = Option<String>)]
+    timeout: Option<DurationString>,
+    /// Maximum number of queries running at the same time. Defaults to and is capped by
+    /// `batch.max_concurrency` from the config.
+    concurrency: Option<usize>,
+}
+
+/// Execute independent queries concurrently, each on any worker having the data
+#[utoipa::path(
+    post,
+    path = "/datasets/{dataset}/batch",
+    tag = "query",
+    params(
+        ("dataset" = String, Path, description = "Dataset name"),
+        BatchParams,
+        ("x-request-id" = Option<String>, Header, description = "Client's ID included in the gateway logs and returned in the response"),
+    ),
+    request_body(content = [Object], description = "Archive queries", content_type = "application/json"),
+    responses(
+        (status = 200, description = "One line per query, in the order of completion", body = BatchItem, content_type = "application/x-ndjson"),
+        (status = 400, description = "Invalid batch", body = ApiError),
+        (status = 404, description = "Unknown dataset", body = ApiError),
+        (status = 406, description = "Uncompressed responses are not accepted", body = ApiError),
+        (status = 429, description = "Not enough requests left for all queries of the batch", body = ApiError),
+    )
+)]
+async fn execute_batch(
+    Path(dataset): Path<String>,
+    Query(BatchParams {
+        timeout,
+        concurrency,
+    }): Query<BatchParams>,
+    Extension(client): Extension<Arc<QueryClient>>,
+    client_id: Option<Extension<ClientId>>,
+    quota: Option<Extension<ClientQuota>>,
+    headers: HeaderMap,
+    queries: String, // request body
+) -> Result<Response, ApiError> {
+    let request_id = request_id(&headers)?;
+    if !Encoding::identity_accepted(&headers) {
+        return Err(ApiError::not_acceptable());
+    }
+    let dataset_id = Config::get()
+        .dataset_id(&dataset)
+        .ok_or_else(|| ApiError::unknown_dataset(&dataset))?;
+    let queries: Vec<Box<RawValue>> = serde_json::from_str(&queries).map_err(|err| {
+        ApiError::bad_request(format!("Batch must be an array of queries: {err}"))
+    })?;
+    let config = Config::get().batch;
+    if queries.len() > config.max_queries {
+        return Err(ApiError::bad_request(format!(
+            "Batch must not contain more than {} queries",
+            config.max_queries
+        )));
+    }
+    log::debug!(
+        "Execute batch of {} queries dataset={dataset} client_id={} request_id={}",
+        queries.len(),
+        display_client(&client_id),
+        request_id.as_deref().unwrap_or("-")
+    );
+
+    let timeout = timeout
+        .map(Into::into)
+        .unwrap_or(Config::get().default_query_timeout)
+        .min(config.max_timeout);
+    let mut concurrency = concurrency
+        .unwrap_or(config.max_concurrency)
+        .clamp(1, config.max_concurrency.max(1));
+
+    // Every query counts as a request. The request itself has been charged already.
+    let guard = match quota {
+        Some(Extension(quota)) => {
+            let requests = queries.len().saturating_sub(1) as u32;
+            let guard = quota.charge(requests, concurrency as u32 - 1)?;
+            concurrency = guard.slots() as usize + 1;
+            Some(guard)
         }
+        None => None,
+    };
+    let stream = Batch::new(
+        client,
+        dataset,
+        dataset_id,
+        queries,
+        Instant::now() + timeout,
+        concurrency,
+    )
+    .with_request_id(request_id)
+    .into_stream();
+    let mut body = Body::from_stream(stream);
+    if let Some(guard) = guard {
+        body = guard.hold_until_sent(body);
     }
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/x-ndjson".parse().unwrap());
+    Ok((StatusCode::OK, headers, body).into_response())
+}
 
-    (StatusCode::OK, headers, data).into_response()
+fn display_client(client_id: &Option<Extension<ClientId>>) -> &str {
+    client_id
+        .as_ref()
+        .map_or("anonymous", |Extension(ClientId(id))| id.as_str())
 }
 
-fn save_exec_plan(exec_plan: Vec<u8>) {
-    tokio::spawn(async move {
-        let mut output_path = std::env::temp_dir();
-        let now = chrono::Utc::now();
-        output_path.extend([format!("exec_plan_{}.json.gz", now.to_rfc3339())]);
-        if let Err(e) = tokio::fs::write(&output_path, exec_plan).await {
-            log::error!("Error saving exec_plan: {e:?}");
-        } else {
-            log::info!("Exec plan saved to {}", output_path.display());
-        }
-    });
+/// ID the client can send to find its requests in the gateway logs
+fn request_id(headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+    let Some(value) = headers.get(REQUEST_ID_HEADER) else {
+        return Ok(None);
+    };
+    let request_id = value
+        .to_str()
+        .map_err(|_| ApiError::bad_request("Request ID must be a visible ASCII string"))?;
+    if request_id.len() > MAX_REQUEST_ID_LEN {
+        return Err(ApiError::bad_request(format!(
+            "Request ID must not be longer than {MAX_REQUEST_ID_LEN} characters"
+        )));
+    }
+    Ok((!request_id.is_empty()).then(|| request_id.to_string()))
+}
+
+/// Middleware returning the `x-request-id` header sent by the client
+async fn echo_request_id(req: Request, next: Next) -> Response {
+    let request_id = req.headers().get(REQUEST_ID_HEADER).cloned();
+    let mut response = next.run(req).await;
+    if let Some(value) = request_id {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+async fn ok_response(
+    query_id: &QueryId,
+    worker_id: PeerId,
+    exec_time: Option<Duration>,
+    result: OkResult,
+    encoding: Encoding,
+    exec_plans: &ExecPlanStore,
+) -> Result<Response, ApiError> {
+    let OkResult {
+        data, exec_plan, ..
+    } = result;
+    if let Some(exec_plan) = exec_plan {
+        exec_plans.save(query_id.id.clone(), exec_plan);
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(QUERY_ID_HEADER, query_id.id.parse().unwrap());
+    headers.insert(WORKER_ID_HEADER, worker_id.to_string().parse().unwrap());
+    if let Some(exec_time) = exec_time {
+        headers.insert(EXEC_TIME_HEADER, (exec_time.as_millis() as u64).into());
+    }
+    headers.insert(COMPRESSED_SIZE_HEADER, data.len().into());
+    if let Some(size) = encoding::gzip_decompressed_size(&data) {
+        headers.insert(DECOMPRESSED_SIZE_HEADER, size.into());
+    }
+
+    let data = encode_body(data, encoding).await?;
+    metrics::response_sent(encoding.as_str(), data.len());
+    let mut response = json_response(encoding, data);
+    response.headers_mut().extend(headers);
+    Ok(response)
 }
 
-fn decode_gzip(data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-    let buffer = Vec::new();
-    let mut decoder = GzDecoder::new(buffer);
-    decoder.write_all(data.as_slice())?;
-    Ok(decoder.finish()?)
+/// Converts gzipped data into the encoding negotiated with the client.
+/// Worker results are gzipped, so they are returned as-is if the client accepts gzip.
+async fn encode_body(data: Vec<u8>, encoding: Encoding) -> Result<Vec<u8>, ApiError> {
+    let levels = Config::get().compression;
+    tokio::task::spawn_blocking(move || encoding::transcode(data, encoding, levels))
+        .await
+        .map_err(ApiError::internal)?
+        .map_err(ApiError::internal)
 }
 
-async fn get_metrics() -> Response {
-    match metrics::gather_metrics() {
-        Ok(metrics) => (StatusCode::OK, metrics).into_response(),
-        Err(err) => server_error(err.to_string()),
+fn json_response(encoding: Encoding, data: Vec<u8>) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/json".parse().unwrap());
+    headers.insert("vary", "accept-encoding".parse().unwrap());
+    if encoding != Encoding::Identity {
+        headers.insert("content-encoding", encoding.as_str().parse().unwrap());
     }
+    (StatusCode::OK, headers, data).into_response()
+}
+
+/// Execution plan collected for a query run with `profiling=true`
+#[utoipa::path(
+    get,
+    path = "/queries/{query_id}/exec-plan",
+    tag = "query",
+    params(("query_id" = String, Path, description = "ID from the `x-query-id` header of the query response")),
+    responses(
+        (status = 200, description = "Exec plan, compressed according to `Accept-Encoding`", body = Object),
+        (status = 404, description = "No plan for the query, or it has expired", body = ApiError),
+        (status = 406, description = "No supported encoding is accepted", body = ApiError),
+    )
+)]
+async fn get_exec_plan(
+    Path(query_id): Path<String>,
+    Extension(exec_plans): Extension<Arc<ExecPlanStore>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let data = exec_plans.get(&query_id).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            ErrorCode::ExecPlanNotFound,
+            format!("No exec plan for query {query_id}"),
+        )
+    })?;
+    let encoding = Encoding::negotiate(&headers).ok_or_else(ApiError::not_acceptable)?;
+    let data = encode_body(data, encoding).await?;
+    Ok(json_response(encoding, data))
+}
+
+/// Prometheus metrics
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "state",
+    responses((status = 200, description = "Metrics in the text exposition format", body = String, content_type = "text/plain"))
+)]
+async fn get_metrics(
+    Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
+) -> Result<String, ApiError> {
+    network_state.read().await.update_breaker_metrics();
+    metrics::gather_metrics().map_err(ApiError::internal)
 }
 
+/// Workers which are only used when no other worker is available
+#[utoipa::path(
+    get,
+    path = "/workers/greylisted",
+    tag = "state",
+    responses((status = 200, description = "Peer IDs of greylisted workers", body = [String]))
+)]
 async fn greylisted_workers(
     Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
 ) -> Response {
     Json(network_state.read().await.greylisted_workers()).into_response()
 }
 
+/// Execution times of the queries sent to each worker, used to prefer faster workers
+#[utoipa::path(
+    get,
+    path = "/workers/latency",
+    tag = "state",
+    responses((status = 200, description = "Latency statistics, fastest workers first", body = [LatencySummary]))
+)]
+async fn worker_latencies(
+    Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
+) -> Response {
+    Json(network_state.read().await.worker_latencies()).into_response()
+}
+
+/// State of a single worker, including its circuit breaker history
+#[utoipa::path(
+    get,
+    path = "/workers/{peer_id}",
+    tag = "state",
+    params(("peer_id" = String, Path, description = "Peer ID of the worker")),
+    responses(
+        (status = 200, description = "Worker state", body = WorkerInfo),
+        (status = 404, description = "Unknown worker", body = ApiError),
+    )
+)]
+async fn get_worker_info(
+    Path(peer_id): Path<PeerId>,
+    Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
+) -> Result<Response, ApiError> {
+    let info = network_state
+        .read()
+        .await
+        .worker_info(&peer_id)
+        .ok_or_else(|| ApiError::unknown_worker(peer_id))?;
+    Ok(Json(info).into_response())
+}
+
+/// All datasets served by the gateway
+#[utoipa::path(
+    get,
+    path = "/datasets",
+    tag = "state",
+    responses((status = 200, description = "Datasets sorted by name", body = [DatasetInfo]))
+)]
+async fn list_datasets(Extension(network_state): Extension<Arc<RwLock<NetworkState>>>) -> Response {
+    Json(network_state.read().await.datasets()).into_response()
+}
+
+/// A single dataset served by the gateway
+#[utoipa::path(
+    get,
+    path = "/datasets/{dataset}",
+    tag = "state",
+    params(("dataset" = String, Path, description = "Dataset name")),
+    responses(
+        (status = 200, description = "Dataset", body = DatasetInfo),
+        (status = 404, description = "Unknown dataset", body = ApiError),
+    )
+)]
+async fn get_dataset(
+    Path(dataset): Path<String>,
+    Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
+) -> Result<Response, ApiError> {
+    let dataset_id = Config::get()
+        .dataset_id(&dataset)
+        .ok_or_else(|| ApiError::unknown_dataset(&dataset))?;
+    let info = network_state
+        .read()
+        .await
+        .dataset_info(&dataset, &dataset_id);
+    Ok(Json(info).into_response())
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+struct OverrideParams {
+    #[serde(default)]
+    reason: String,
+    /// Greylisting time. Defaults to `worker_greylist_time_sec` from the config.
+    duration_sec: Option<u64>,
+}
+
+/// Use the worker only if no other worker is available
+#[utoipa::path(
+    post,
+    path = "/admin/workers/{worker_id}/greylist",
+    tag = "admin",
+    params(("worker_id" = String, Path, description = "Peer ID of the worker")),
+    request_body = OverrideParams,
+    responses(
+        (status = 200, description = "Override has been set", body = WorkerOverride),
+        (status = 400, description = "Invalid duration", body = ApiError),
+    )
+)]
+async fn greylist_worker(
+    Path(worker_id): Path<PeerId>,
+    client_id: Option<Extension<ClientId>>,
+    Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
+    Extension(overrides_store): Extension<Arc<WorkerOverridesStore>>,
+    JsonBody(params): JsonBody<OverrideParams>,
+) -> Result<Response, ApiError> {
+    let duration = params
+        .duration_sec
+        .map_or(Config::get().worker_greylist_time, Duration::from_secs);
+    let worker_override = WorkerOverride::new(
+        worker_id,
+        OverrideKind::Greylist,
+        params.reason,
+        display_client(&client_id).to_string(),
+        Some(duration),
+    )
+    .ok_or_else(|| ApiError::bad_request("duration_sec is too large"))?;
+    set_worker_override(worker_override, &network_state, &overrides_store).await
+}
+
+/// Never use the worker
+#[utoipa::path(
+    post,
+    path = "/admin/workers/{worker_id}/ban",
+    tag = "admin",
+    params(("worker_id" = String, Path, description = "Peer ID of the worker")),
+    request_body = OverrideParams,
+    responses(
+        (status = 200, description = "Override has been set", body = WorkerOverride),
+        (status = 400, description = "Invalid duration", body = ApiError),
+    )
+)]
+async fn ban_worker(
+    Path(worker_id): Path<PeerId>,
+    client_id: Option<Extension<ClientId>>,
+    Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
+    Extension(overrides_store): Extension<Arc<WorkerOverridesStore>>,
+    JsonBody(params): JsonBody<OverrideParams>,
+) -> Result<Response, ApiError> {
+    let worker_override = WorkerOverride::new(
+        worker_id,
+        OverrideKind::Ban,
+        params.reason,
+        display_client(&client_id).to_string(),
+        params.duration_sec.map(Duration::from_secs),
+    )
+    .ok_or_else(|| ApiError::bad_request("duration_sec is too large"))?;
+    set_worker_override(worker_override, &network_state, &overrides_store).await
+}
+
+async fn set_worker_override(
+    worker_override: WorkerOverride,
+    network_state: &RwLock<NetworkState>,
+    overrides_store: &WorkerOverridesStore,
+) -> Result<Response, ApiError> {
+    overrides_store
+        .save(&worker_override)
+        .await
+        .map_err(ApiError::internal)?;
+    network_state
+        .write()
+        .await
+        .set_worker_override(worker_override.clone());
+    Ok(Json(worker_override).into_response())
+}
+
+/// Remove the manual override and the automatic greylisting of the worker
+#[utoipa::path(
+    delete,
+    path = "/admin/workers/{worker_id}/override",
+    tag = "admin",
+    params(("worker_id" = String, Path, description = "Peer ID of the worker")),
+    responses(
+        (status = 204, description = "Worker has been restored"),
+        (status = 404, description = "Worker has nothing to restore", body = ApiError),
+    )
+)]
+async fn restore_worker(
+    Path(worker_id): Path<PeerId>,
+    client_id: Option<Extension<ClientId>>,
+    Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
+    Extension(overrides_store): Extension<Arc<WorkerOverridesStore>>,
+) -> Result<StatusCode, ApiError> {
+    log::info!(
+        "Worker {worker_id} restored by {}",
+        display_client(&client_id)
+    );
+    let removed = overrides_store
+        .remove(worker_id)
+        .await
+        .map_err(ApiError::internal)?;
+    let restored = network_state.write().await.restore_worker(&worker_id);
+    if !removed && !restored {
+        return Err(ApiError::unknown_worker(worker_id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Delete expired overrides from the store and from the network state
+async fn purge_expired_overrides(
+    network_state: Arc<RwLock<NetworkState>>,
+    overrides_store: Arc<WorkerOverridesStore>,
+) {
+    loop {
+        tokio::time::sleep(OVERRIDES_PURGE_INTERVAL).await;
+        network_state.write().await.purge_expired_overrides();
+        match overrides_store.purge_expired().await {
+            Ok(0) => {}
+            Ok(n) => log::info!("Deleted {n} expired worker overrides"),
+            Err(e) => log::error!("Couldn't delete expired worker overrides: {e:?}"),
+        }
+    }
+}
+
+/// All active manual overrides
+#[utoipa::path(
+    get,
+    path = "/admin/workers/overrides",
+    tag = "admin",
+    responses((status = 200, description = "Active overrides", body = [WorkerOverride]))
+)]
+async fn worker_overrides(
+    Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
+) -> Response {
+    Json(network_state.read().await.worker_overrides()).into_response()
+}
+
+/// Ranges and heights reported by the workers, per dataset ID
+#[utoipa::path(
+    get,
+    path = "/network/state",
+    tag = "state",
+    responses((status = 200, description = "Network state", body = Object))
+)]
 async fn get_network_state(
     Extension(network_state): Extension<Arc<RwLock<NetworkState>>>,
 ) -> Response {
     Json(network_state.read().await.network_state()).into_response()
 }
 
+/// Check that the process and the query server loop are responsive
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    tag = "public",
+    responses(
+        (status = 200, description = "Alive", body = HealthStatus),
+        (status = 503, description = "Some of the checks have failed", body = HealthStatus),
+    )
+)]
+async fn liveness(Extension(client): Extension<Arc<QueryClient>>) -> Response {
+    let responsive = client.server_responsive(LIVENESS_TIMEOUT).await;
+    let message = if responsive {
+        "Query server loop is responsive"
+    } else {
+        "Query server loop hasn't responded in time"
+    };
+    health_response(HealthStatus::new(vec![HealthCheck::new(
+        "server_loop",
+        responsive,
+        message,
+    )]))
+}
+
+/// Check that the gateway is ready to serve queries
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "public",
+    responses(
+        (status = 200, description = "Ready", body = HealthStatus),
+        (status = 503, description = "Some of the checks have failed", body = HealthStatus),
+    )
+)]
+async fn readiness(Extension(network_state): Extension<Arc<RwLock<NetworkState>>>) -> Response {
+    health_response(health::readiness(&*network_state.read().await))
+}
+
+fn health_response(status: HealthStatus) -> Response {
+    let status_code = if status.ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status_code, Json(status)).into_response()
+}
+
+/// This document
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "public",
+    responses((status = 200, description = "OpenAPI 3 description of the HTTP API", body = Object))
+)]
+async fn get_openapi() -> Response {
+    Json(ApiDoc::openapi()).into_response()
+}
+
+struct Route {
+    /// Scope the API key has to have. Public routes have no scope.
+    scope: Option<Scope>,
+    method: Method,
+    path: &'static str,
+    handler: MethodRouter,
+}
+
+fn route<H, T>(scope: Option<Scope>, method: Method, path: &'static str, handler: H) -> Route
+where
+    H: Handler<T, ()>,
+    T: 'static,
+{
+    let filter = MethodFilter::try_from(method.clone()).expect("Unsupported method");
+    Route {
+        scope,
+        method,
+        path,
+        handler: on(filter, handler),
+    }
+}
+
+/// All routes served by the gateway. Each one has to be documented in [`ApiDoc`].
+fn routes() -> Vec<Route> {
+    let query = Some(Scope::Query);
+    let state = Some(Scope::State);
+    let admin = Some(Scope::Admin);
+    vec![
+        route(query, Method::GET, "/network/:dataset/height", get_height),
+        route(
+            query,
+            Method::GET,
+            "/datasets/:dataset/height/stream",
+            stream_heights,
+        ),
+        route(
+            query,
+            Method::GET,
+            "/network/:dataset/:start_block/worker",
+            get_worker,
+        ),
+        route(
+            query,
+            Method::POST,
+            "/query/:dataset_id/:worker_id",
+            execute_query,
+        ),
+        route(
+            query,
+            Method::POST,
+            "/datasets/:dataset/query",
+            execute_dataset_query,
+        ),
+        route(
+            query,
+            Method::POST,
+            "/datasets/:dataset/stream",
+            stream_dataset_range,
+        ),
+        route(
+            query,
+            Method::POST,
+            "/datasets/:dataset/batch",
+            execute_batch,
+        ),
+        route(
+            query,
+            Method::GET,
+            "/queries/:query_id/exec-plan",
+            get_exec_plan,
+        ),
+        route(state, Method::GET, "/network/state", get_network_state),
+        route(state, Method::GET, "/metrics", get_metrics),
+        route(
+            state,
+            Method::GET,
+            "/workers/greylisted",
+            greylisted_workers,
+        ),
+        route(state, Method::GET, "/workers/latency", worker_latencies),
+        route(state, Method::GET, "/workers/:peer_id", get_worker_info),
+        route(state, Method::GET, "/datasets", list_datasets),
+        route(state, Method::GET, "/datasets/:dataset", get_dataset),
+        route(
+            admin,
+            Method::GET,
+            "/admin/workers/overrides",
+            worker_overrides,
+        ),
+        route(
+            admin,
+            Method::POST,
+            "/admin/workers/:worker_id/greylist",
+            greylist_worker,
+        ),
+        route(
+            admin,
+            Method::POST,
+            "/admin/workers/:worker_id/ban",
+            ban_worker,
+        ),
+        route(
+            admin,
+            Method::DELETE,
+            "/admin/workers/:worker_id/override",
+            restore_worker,
+        ),
+        route(None, Method::GET, "/health/live", liveness),
+        route(None, Method::GET, "/health/ready", readiness),
+        route(None, Method::GET, "/openapi.json", get_openapi),
+    ]
+}
+
 pub async fn run_server(
     query_client: QueryClient,
     network_state: Arc<RwLock<NetworkState>>,
+    overrides_store: WorkerOverridesStore,
+    url_signer: UrlSigner,
     addr: &SocketAddr,
+    tls: Option<TlsFiles>,
 ) -> anyhow::Result<()> {
-    log::info!("Starting HTTP server listening on {addr}");
-    let app = Router::new()
-        .route("/network/:dataset/height", get(get_height))
-        .route("/network/:dataset/:start_block/worker", get(get_worker))
-        .route("/network/state", get(get_network_state))
-        .route("/query/:dataset_id/:worker_id", post(execute_query))
-        .route("/metrics", get(get_metrics))
-        .route("/workers/greylisted", get(greylisted_workers))
+    let protocol = if tls.is_some() { "HTTPS" } else { "HTTP" };
+    log::info!("Starting {protocol} server listening on {addr}");
+    if Config::get().api_keys.is_empty() {
+        log::warn!("No API keys configured. HTTP API is accessible without authentication");
+    }
+    let mut public_routes = Router::new();
+    let mut query_routes = Router::new();
+    let mut state_routes = Router::new();
+    let mut admin_routes = Router::new();
+    for Route {
+        scope,
+        method,
+        path,
+        handler,
+    } in routes()
+    {
+        log::debug!("Serving {method} {path}");
+        let router = match scope {
+            None => &mut public_routes,
+            Some(Scope::Query) => &mut query_routes,
+            Some(Scope::State) => &mut state_routes,
+            Some(Scope::Admin) => &mut admin_routes,
+        };
+        *router = std::mem::take(router).route(path, handler);
+    }
+    let query_routes = query_routes
+        .route_layer(middleware::from_fn_with_state(
+            Arc::new(RateLimiter::default()),
+            rate_limit::throttle,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            Scope::Query,
+            auth::authenticate,
+        ));
+    let state_routes = state_routes.route_layer(middleware::from_fn_with_state(
+        Scope::State,
+        auth::authenticate,
+    ));
+    let admin_routes = admin_routes.route_layer(middleware::from_fn_with_state(
+        Scope::Admin,
+        auth::authenticate,
+    ));
+    let overrides_store = Arc::new(overrides_store);
+    tokio::spawn(purge_expired_overrides(
+        network_state.clone(),
+        overrides_store.clone(),
+    ));
+    let app = public_routes
+        .merge(query_routes)
+        .merge(state_routes)
+        .merge(admin_routes)
         .layer(Extension(Arc::new(query_client)))
-        .layer(Extension(network_state));
+        .layer(Extension(network_state))
+        .layer(Extension(overrides_store))
+        .layer(Extension(Arc::new(url_signer)))
+        .layer(Extension(Arc::new(ExecPlanStore::new(
+            &Config::get().exec_plans,
+        ))))
+        .layer(middleware::from_fn(api_error::negotiate))
+        .layer(middleware::from_fn(echo_request_id));
 
     let mut sigint = signal(SignalKind::interrupt())?;
     let mut sigterm = signal(SignalKind::terminate())?;
@@ -195,11 +1349,67 @@ pub async fn run_server(
         }
     };
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown)
-        .await?;
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            axum::serve(listener, make_service)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+        }
+        Some(files) => {
+            let config = RustlsConfig::from_config(Arc::new(tls::server_config(&files)?));
+            tokio::spawn(tls::reload_on_change(files, config.clone()));
+            let handle = Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            axum_server::bind(*addr)
+                .acceptor(ClientCertAcceptor::new(config))
+                .handle(handle)
+                .serve(make_service)
+                .await?;
+        }
+    }
 
     log::info!("HTTP server stopped");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use utoipa::openapi::PathItemType;
+
+    use super::*;
+
+    #[test]
+    fn all_routes_documented() {
+        let doc = ApiDoc::openapi();
+        for Route { method, path, .. } in routes() {
+            let doc_path = path
+                .split('/')
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(param) => format!("{{{param}}}"),
+                    None => segment.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            let item_type = match method {
+                Method::GET => PathItemType::Get,
+                Method::POST => PathItemType::Post,
+                Method::DELETE => PathItemType::Delete,
+                _ => panic!("Unexpected method {method}"),
+            };
+            let documented = doc
+                .paths
+                .paths
+                .get(&doc_path)
+                .is_some_and(|item| item.operations.contains_key(&item_type));
+            assert!(documented, "{method} {path} is missing from ApiDoc");
+        }
+    }
+}
@@ -11,18 +11,35 @@ use tokio::sync::RwLock;
 
 use crate::config::Config;
 use crate::network_state::NetworkState;
+use crate::tls::TlsFiles;
+use crate::worker_overrides::WorkerOverridesStore;
+use crate::worker_urls::UrlSigner;
 
 mod allocations;
+mod api_error;
+mod auth;
+mod batch;
 mod chain_updates;
+mod circuit_breaker;
 mod client;
 mod config;
+mod encoding;
+mod exec_plans;
+mod health;
+mod height_stream;
 mod http_server;
 mod metrics;
 mod network_state;
 mod query;
+mod range_stream;
+mod rate_limit;
 mod scheme_extractor;
 mod server;
 mod task;
+mod tls;
+mod worker_overrides;
+mod worker_stats;
+mod worker_urls;
 
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
@@ -55,6 +72,46 @@ struct Cli {
         default_value = "allocations.db"
     )]
     allocations_db_path: PathBuf,
+
+    #[arg(
+        long,
+        env,
+        help = "Path to database file with manual worker overrides",
+        default_value = "worker_overrides.db"
+    )]
+    worker_overrides_db_path: PathBuf,
+
+    #[arg(
+        long,
+        env,
+        help = "Path to PEM certificate chain. Enables HTTPS",
+        requires = "tls_key_path"
+    )]
+    tls_cert_path: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env,
+        help = "Path to PEM private key of the certificate",
+        requires = "tls_cert_path"
+    )]
+    tls_key_path: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env,
+        help = "Path to PEM CA bundle used to verify client certificates",
+        requires = "tls_cert_path"
+    )]
+    tls_client_ca_path: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env,
+        help = "Secret used to sign worker URLs. Should be shared by all gateway instances behind one address",
+        hide_env_values = true
+    )]
+    worker_url_secret: Option<String>,
 }
 
 #[tokio::main]
@@ -84,7 +141,14 @@ async fn main() -> anyhow::Result<()> {
     // Initialize allocated/spent CU metrics with zeros
     let workers = contract_client.active_workers().await?;
     metrics::init_workers(workers.iter().map(|w| w.peer_id.to_string()));
-    let network_state = Arc::new(RwLock::new(NetworkState::new(workers)));
+    let mut network_state = NetworkState::new(workers);
+
+    // Restore workers greylisted or banned manually
+    let overrides_store = WorkerOverridesStore::new(args.worker_overrides_db_path).await?;
+    for worker_override in overrides_store.load().await? {
+        network_state.set_worker_override(worker_override);
+    }
+    let network_state = Arc::new(RwLock::new(network_state));
 
     // Start query client
     let query_client = client::get_client(
@@ -98,5 +162,21 @@ async fn main() -> anyhow::Result<()> {
     .await?;
 
     // Start HTTP server
-    http_server::run_server(query_client, network_state, &args.http_listen).await
+    let tls = args
+        .tls_cert_path
+        .zip(args.tls_key_path)
+        .map(|(cert_path, key_path)| TlsFiles {
+            cert_path,
+            key_path,
+            client_ca_path: args.tls_client_ca_path,
+        });
+    http_server::run_server(
+        query_client,
+        network_state,
+        overrides_store,
+        UrlSigner::new(args.worker_url_secret)?,
+        &args.http_listen,
+        tls,
+    )
+    .await
 }
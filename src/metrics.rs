@@ -1,10 +1,12 @@
 use std::ops::Deref;
 
+use crate::circuit_breaker::BreakerState;
+use crate::config::{Config, DatasetId};
 use crate::task::FinishedTask;
 use lazy_static::lazy_static;
 use prometheus::{
-    register_histogram_vec, register_int_gauge, register_int_gauge_vec, HistogramVec, IntGauge,
-    IntGaugeVec, TextEncoder,
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
 };
 
 lazy_static! {
@@ -29,6 +31,61 @@ lazy_static! {
     .unwrap();
     static ref CURRENT_EPOCH: IntGauge =
         register_int_gauge!("current_epoch", "current epoch number").unwrap();
+    static ref CANCELLED_QUERIES: IntCounterVec = register_int_counter_vec!(
+        "cancelled_queries",
+        "number of queries cancelled because the client disconnected",
+        &["dataset"]
+    )
+    .unwrap();
+    static ref AUTHENTICATED_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "authenticated_requests",
+        "number of HTTP requests authenticated with an API key",
+        &["key_id", "scope"]
+    )
+    .unwrap();
+    static ref RESPONSE_BYTES: HistogramVec = register_histogram_vec!(
+        "response_bytes",
+        "size of query result bodies sent to clients in bytes, labeled with content encoding",
+        &["encoding"],
+        prometheus::exponential_buckets(1024.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    static ref WORKER_CHECK_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "worker_check_failures",
+        "number of queries sent to workers which shouldn't get them, labeled with problem and policy",
+        &["problem", "policy"]
+    )
+    .unwrap();
+    static ref IN_FLIGHT_QUERIES: IntGaugeVec = register_int_gauge_vec!(
+        "in_flight_queries",
+        "number of queries sent to the worker and not finished yet",
+        &["worker_id"]
+    )
+    .unwrap();
+    static ref BREAKER_TRANSITIONS: IntCounterVec = register_int_counter_vec!(
+        "circuit_breaker_transitions",
+        "number of times the worker's circuit breaker has opened or closed, labeled with worker_id and state",
+        &["worker_id", "state"]
+    )
+    .unwrap();
+    static ref BREAKER_STATE: IntGaugeVec = register_int_gauge_vec!(
+        "circuit_breaker_state",
+        "state of the worker's circuit breaker: 0 closed, 1 half-open, 2 open",
+        &["worker_id"]
+    )
+    .unwrap();
+    static ref WORKER_URL_CHECKS: IntCounterVec = register_int_counter_vec!(
+        "worker_url_checks",
+        "number of queries sent to worker URLs, labeled with token check result",
+        &["result"]
+    )
+    .unwrap();
+    static ref THROTTLED_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "throttled_requests",
+        "number of HTTP requests rejected by rate limiting, labeled with key_id and reason",
+        &["key_id", "reason"]
+    )
+    .unwrap();
 }
 
 pub fn init_workers<T, S>(workers: T)
@@ -70,6 +127,63 @@ pub fn query_finished(task: &FinishedTask) {
         .observe(task.exec_time_ms() as f64 / 1000.0);
 }
 
+pub fn set_in_flight(worker_id: &str, in_flight: usize) {
+    IN_FLIGHT_QUERIES
+        .with_label_values(&[worker_id])
+        .set(in_flight as i64);
+}
+
+pub fn breaker_state_changed(worker_id: &str, state: BreakerState) {
+    BREAKER_TRANSITIONS
+        .with_label_values(&[worker_id, state.as_str()])
+        .inc();
+    set_breaker_state(worker_id, state);
+}
+
+pub fn set_breaker_state(worker_id: &str, state: BreakerState) {
+    let value = match state {
+        BreakerState::Closed => 0,
+        BreakerState::HalfOpen => 1,
+        BreakerState::Open => 2,
+    };
+    BREAKER_STATE.with_label_values(&[worker_id]).set(value);
+}
+
+pub fn query_cancelled(dataset_id: &DatasetId) {
+    let dataset = Config::get()
+        .dataset_name(dataset_id)
+        .unwrap_or(&dataset_id.0);
+    CANCELLED_QUERIES.with_label_values(&[dataset]).inc();
+}
+
+pub fn authenticated_request(key_id: &str, scope: &str) {
+    AUTHENTICATED_REQUESTS
+        .with_label_values(&[key_id, scope])
+        .inc();
+}
+
+pub fn request_throttled(key_id: &str, reason: &str) {
+    THROTTLED_REQUESTS
+        .with_label_values(&[key_id, reason])
+        .inc();
+}
+
+pub fn worker_check_failed(problem: &str, policy: &str) {
+    WORKER_CHECK_FAILURES
+        .with_label_values(&[problem, policy])
+        .inc();
+}
+
+pub fn worker_url_checked(result: &str) {
+    WORKER_URL_CHECKS.with_label_values(&[result]).inc();
+}
+
+pub fn response_sent(encoding: &str, bytes: usize) {
+    RESPONSE_BYTES
+        .with_label_values(&[encoding])
+        .observe(bytes as f64);
+}
+
 pub fn gather_metrics() -> anyhow::Result<String> {
     Ok(TextEncoder::new().encode_to_string(&prometheus::gather())?)
 }
@@ -1,35 +1,87 @@
 use std::cmp::max;
 use std::collections::{HashMap, HashSet};
-use std::time::Instant;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant, SystemTime};
 
+use chrono::{DateTime, Utc};
 use contract_client::Worker;
-use rand::prelude::IteratorRandom;
+use rand::prelude::{IteratorRandom, SliceRandom};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tabled::Tabled;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
 
 use subsquid_messages::RangeSet;
 use subsquid_network_transport::PeerId;
 
-use crate::config::{Config, DatasetId};
+use crate::circuit_breaker::{BreakerState, BreakerSummary, CircuitBreaker, Failure};
+use crate::config::{
+    Config, DatasetId, RangePolicy, WorkerSelectionConfig, WorkerSelectionStrategy,
+};
+use crate::metrics;
+use crate::query::QueryResult;
+use crate::task::FinishedTask;
+use crate::worker_overrides::{OverrideKind, WorkerOverride};
+use crate::worker_stats::{LatencySummary, WorkerLatency};
+
+/// Subscribers lagging behind by more updates than this miss some of them
+const HEIGHT_UPDATES_CAPACITY: usize = 1024;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct DatasetState {
     worker_ranges: HashMap<PeerId, RangeSet>,
     highest_seen_block: u32,
+    #[serde(skip)]
+    last_range_update: Option<SystemTime>,
 }
 
 impl DatasetState {
-    pub fn get_workers_with_block(&self, block: u32) -> impl Iterator<Item = PeerId> + '_ {
+    /// Workers having `block`, with the last block of their contiguous range containing it
+    pub fn get_workers_with_block(&self, block: u32) -> impl Iterator<Item = (PeerId, u32)> + '_ {
+        self.worker_ranges
+            .keys()
+            .filter_map(move |peer_id| Some((*peer_id, self.worker_range_end(peer_id, block)?)))
+    }
+
+    /// Last block of the worker's contiguous range containing `block`
+    pub fn worker_range_end(&self, peer_id: &PeerId, block: u32) -> Option<u32> {
         self.worker_ranges
+            .get(peer_id)?
+            .ranges
             .iter()
-            .filter_map(move |(peer_id, range_set)| range_set.has(block).then_some(*peer_id))
+            .find(|range| range.begin <= block && block <= range.end)
+            .map(|range| range.end)
+    }
+
+    /// Whether the worker has reported having `block`, or any data if the block is unknown
+    pub fn worker_has_data(&self, peer_id: &PeerId, block: Option<u32>) -> bool {
+        self.worker_ranges
+            .get(peer_id)
+            .is_some_and(|range_set| match block {
+                Some(block) => range_set.has(block),
+                None => !range_set.ranges.is_empty(),
+            })
     }
 
-    pub fn update(&mut self, peer_id: PeerId, state: RangeSet) {
+    /// Returns true if the worker's ranges have changed
+    pub fn update(&mut self, peer_id: PeerId, state: RangeSet) -> bool {
         if let Some(range) = state.ranges.last() {
             self.highest_seen_block = max(self.highest_seen_block, range.end)
         }
+        let changed = self.worker_ranges.get(&peer_id) != Some(&state);
+        if changed {
+            self.last_range_update = Some(SystemTime::now());
+        }
         self.worker_ranges.insert(peer_id, state);
+        changed
+    }
+
+    pub fn height(&self) -> DatasetHeight {
+        DatasetHeight {
+            highest_indexable_block: self.highest_indexable_block(),
+            highest_seen_block: self.highest_seen_block,
+        }
     }
 
     pub fn highest_indexable_block(&self) -> u32 {
@@ -73,13 +125,127 @@ impl<'a> DatasetSummary<'a> {
     }
 }
 
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WorkerInfo {
+    worker_id: String,
+    /// Registered on chain
+    registered: bool,
+    /// Has sent a ping within `worker_inactive_threshold`
+    active: bool,
+    /// Manual override set by an admin, if not expired
+    #[serde(rename = "override")]
+    worker_override: Option<OverrideKind>,
+    /// Compute units left in the current epoch
+    remaining_cus: Option<u32>,
+    /// Queries sent to the worker and not finished yet
+    in_flight: usize,
+    latency: Option<LatencySummary>,
+    circuit_breaker: BreakerSummary,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DatasetInfo {
+    name: String,
+    #[schema(value_type = String)]
+    id: DatasetId,
+    /// Source URL decoded from the dataset ID
+    url: Option<String>,
+    highest_indexable_block: u32,
+    highest_seen_block: u32,
+    /// Workers having any data for the dataset and sending pings
+    active_workers: usize,
+    /// Active workers that can be sent queries right now
+    available_workers: usize,
+    /// Last time any of the workers' ranges for the dataset changed (RFC 3339)
+    last_range_update: Option<String>,
+}
+
+/// Reason why a query shouldn't be sent to a worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerProblem {
+    /// Not registered on chain
+    Unregistered,
+    /// Banned manually
+    Banned,
+    /// Hasn't sent a ping within `worker_inactive_threshold`
+    Inactive,
+    /// The gateway has no compute units allocated for the worker
+    NoAllocation,
+    /// Greylisted manually or after failed queries
+    Greylisted,
+    /// Doesn't have the dataset or the query's first block
+    MissingData,
+}
+
+impl WorkerProblem {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkerProblem::Unregistered => "unregistered",
+            WorkerProblem::Banned => "banned",
+            WorkerProblem::Inactive => "inactive",
+            WorkerProblem::NoAllocation => "no_allocation",
+            WorkerProblem::Greylisted => "greylisted",
+            WorkerProblem::MissingData => "missing_data",
+        }
+    }
+
+    /// Queries are never sent to workers with these problems, whatever the `worker_check_policy`
+    pub fn is_hard(&self) -> bool {
+        matches!(self, WorkerProblem::Unregistered | WorkerProblem::Banned)
+    }
+}
+
+impl Display for WorkerProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct DatasetHeight {
+    pub highest_indexable_block: u32,
+    pub highest_seen_block: u32,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct HeightUpdate {
+    /// Dataset name
+    pub dataset: String,
+    #[serde(flatten)]
+    pub height: DatasetHeight,
+}
+
+struct HeightUpdates {
+    sender: broadcast::Sender<HeightUpdate>,
+    /// Last heights sent for each dataset
+    last_sent: HashMap<DatasetId, DatasetHeight>,
+}
+
+impl Default for HeightUpdates {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(HEIGHT_UPDATES_CAPACITY).0,
+            last_sent: Default::default(),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct NetworkState {
     dataset_states: HashMap<DatasetId, DatasetState>,
     last_pings: HashMap<PeerId, Instant>,
-    worker_greylist: HashMap<PeerId, Instant>,
+    circuit_breakers: HashMap<PeerId, CircuitBreaker>,
     workers_without_allocation: HashSet<PeerId>,
     registered_workers: HashSet<PeerId>,
+    worker_overrides: HashMap<PeerId, WorkerOverride>,
+    last_chain_update: Option<SystemTime>,
+    height_updates: HeightUpdates,
+    worker_latency: HashMap<PeerId, WorkerLatency>,
+    /// Compute units left for each worker, updated with every spent query
+    /// and synced with the allocations DB on chain updates
+    remaining_cus: HashMap<PeerId, u32>,
+    /// Number of queries sent to each worker and not finished yet
+    in_flight: HashMap<PeerId, usize>,
 }
 
 impl NetworkState {
@@ -90,36 +256,215 @@ impl NetworkState {
     }
 
     pub fn find_worker(&self, dataset_id: &DatasetId, start_block: u32) -> Option<PeerId> {
+        self.find_worker_except(dataset_id, start_block, &[])
+    }
+
+    /// Same as `find_worker`, but never returns any of the `excluded` workers
+    pub fn find_worker_except(
+        &self,
+        dataset_id: &DatasetId,
+        start_block: u32,
+        excluded: &[PeerId],
+    ) -> Option<PeerId> {
         log::debug!("Looking for worker dataset_id={dataset_id}, start_block={start_block}");
         let dataset_state = match self.dataset_states.get(dataset_id) {
             None => return None,
             Some(state) => state,
         };
 
-        // Choose a random active worker having the requested start_block
-        let mut worker = dataset_state
+        // Choose an active worker having the requested start_block
+        let candidates: Vec<_> = dataset_state
             .get_workers_with_block(start_block)
-            .filter(|peer_id| self.worker_available(peer_id, false))
-            .choose(&mut rand::thread_rng());
+            .filter(|(peer_id, _)| !excluded.contains(peer_id))
+            .filter(|(peer_id, _)| self.worker_available(peer_id, false))
+            .collect();
+        let unsaturated: Vec<_> = candidates
+            .iter()
+            .copied()
+            .filter(|(peer_id, _)| !self.worker_saturated(peer_id))
+            .collect();
+        let mut worker = self.choose_worker(
+            &unsaturated,
+            start_block,
+            &Config::get().worker_selection,
+            &mut rand::thread_rng(),
+        );
 
         // If no worker is found, try grey-listed workers
+        let mut greylisted = Vec::new();
         if worker.is_none() {
-            worker = dataset_state
+            greylisted = dataset_state
                 .get_workers_with_block(start_block)
-                .filter(|peer_id| self.worker_available(peer_id, true))
-                .choose(&mut rand::thread_rng());
+                .filter(|(peer_id, _)| !excluded.contains(peer_id))
+                .filter(|(peer_id, _)| self.worker_available(peer_id, true))
+                .collect();
+            worker = greylisted
+                .iter()
+                .filter(|(peer_id, _)| !self.worker_saturated(peer_id))
+                .choose(&mut rand::thread_rng())
+                .map(|(peer_id, _)| *peer_id);
+        }
+
+        // If all workers are saturated, the query will wait for the least loaded one
+        if worker.is_none() {
+            worker = candidates
+                .into_iter()
+                .chain(greylisted)
+                .map(|(peer_id, _)| peer_id)
+                .min_by_key(|peer_id| self.in_flight(peer_id));
         }
 
         worker
     }
 
+    /// `candidates` are workers with the last blocks of their ranges containing `start_block`
+    fn choose_worker(
+        &self,
+        candidates: &[(PeerId, u32)],
+        start_block: u32,
+        config: &WorkerSelectionConfig,
+        rng: &mut impl Rng,
+    ) -> Option<PeerId> {
+        let furthest;
+        let candidates = match config.range_policy {
+            RangePolicy::Require => {
+                let max_end = candidates.iter().map(|(_, range_end)| *range_end).max()?;
+                furthest = candidates
+                    .iter()
+                    .copied()
+                    .filter(|(_, range_end)| *range_end == max_end)
+                    .collect::<Vec<_>>();
+                &furthest
+            }
+            RangePolicy::Ignore | RangePolicy::Prefer => candidates,
+        };
+
+        if config.strategy == WorkerSelectionStrategy::Random {
+            return self.sample_worker(candidates, start_block, config, rng);
+        }
+        // Not weighted, so that workers with few compute units left or short ranges
+        // get measured as well
+        if rng.gen_bool(config.exploration_rate) {
+            return candidates.choose(rng).map(|(worker_id, _)| *worker_id);
+        }
+
+        // Power of two choices: sending every query to the fastest worker would overload it
+        let first = self.sample_worker(candidates, start_block, config, rng)?;
+        let others: Vec<_> = candidates
+            .iter()
+            .copied()
+            .filter(|(worker_id, _)| *worker_id != first)
+            .collect();
+        let Some(second) = self.sample_worker(&others, start_block, config, rng) else {
+            return Some(first);
+        };
+        // Expected time for the worker to finish all its queries, including the new one
+        let load = |worker_id| self.in_flight(worker_id) as u32 + 1;
+        let score = |worker_id| {
+            self.worker_latency
+                .get(worker_id)
+                .and_then(WorkerLatency::ewma)
+                .map(|latency| latency * load(worker_id))
+        };
+        match (score(&first), score(&second)) {
+            (Some(first_score), Some(second_score)) if second_score < first_score => Some(second),
+            // Workers without measurements are preferred, so that they get measured
+            (Some(_), None) => Some(second),
+            (None, None) if load(&second) < load(&first) => Some(second),
+            _ => Some(first),
+        }
+    }
+
+    /// Random candidate. If allocations are balanced, workers having more compute units left
+    /// are more likely to be picked, and workers having none are only picked if all are out.
+    /// Workers whose allocations are not known yet, e.g. registered since the last chain update,
+    /// are weighted as if they had the mean of the known allocations.
+    /// If longer ranges are preferred, the chance is also proportional to the number of blocks
+    /// the worker has starting from `start_block`.
+    fn sample_worker(
+        &self,
+        candidates: &[(PeerId, u32)],
+        start_block: u32,
+        config: &WorkerSelectionConfig,
+        rng: &mut impl Rng,
+    ) -> Option<PeerId> {
+        let prefer_ranges = config.range_policy == RangePolicy::Prefer;
+        if config.balance_allocations || prefer_ranges {
+            let known_cus: Vec<_> = candidates
+                .iter()
+                .filter_map(|(worker_id, _)| self.remaining_cus.get(worker_id))
+                .map(|cus| *cus as f64)
+                .collect();
+            let mean_cus = if known_cus.is_empty() {
+                1.0
+            } else {
+                known_cus.iter().sum::<f64>() / known_cus.len() as f64
+            };
+            let weight = |(worker_id, range_end): &(PeerId, u32)| {
+                let mut weight = 1.0;
+                if config.balance_allocations {
+                    weight *= self
+                        .remaining_cus
+                        .get(worker_id)
+                        .map_or(mean_cus, |cus| *cus as f64);
+                }
+                if prefer_ranges {
+                    weight *= (range_end.saturating_sub(start_block) + 1) as f64;
+                }
+                weight
+            };
+            if let Ok((worker_id, _)) = candidates.choose_weighted(rng, weight) {
+                return Some(*worker_id);
+            }
+        }
+        candidates.choose(rng).map(|(worker_id, _)| *worker_id)
+    }
+
     fn worker_available(&self, worker_id: &PeerId, allow_greylisted: bool) -> bool {
         self.registered_workers.contains(worker_id)
+            && self.worker_override(worker_id) != Some(OverrideKind::Ban)
             && self.worker_has_allocation(worker_id)
             && self.worker_active(worker_id)
             && (allow_greylisted || !self.worker_greylisted(worker_id))
     }
 
+    /// Returns the first reason why the worker shouldn't get a query for the dataset
+    /// starting at `block`, if any
+    pub fn check_worker(
+        &self,
+        worker_id: &PeerId,
+        dataset_id: &DatasetId,
+        block: Option<u32>,
+    ) -> Option<WorkerProblem> {
+        if !self.registered_workers.contains(worker_id) {
+            Some(WorkerProblem::Unregistered)
+        } else if self.worker_override(worker_id) == Some(OverrideKind::Ban) {
+            Some(WorkerProblem::Banned)
+        } else if !self.worker_active(worker_id) {
+            Some(WorkerProblem::Inactive)
+        } else if !self.worker_has_allocation(worker_id) {
+            Some(WorkerProblem::NoAllocation)
+        } else if self.worker_greylisted(worker_id) {
+            Some(WorkerProblem::Greylisted)
+        } else if !self
+            .dataset_states
+            .get(dataset_id)
+            .is_some_and(|state| state.worker_has_data(worker_id, block))
+        {
+            Some(WorkerProblem::MissingData)
+        } else {
+            None
+        }
+    }
+
+    /// Number of workers which have sent a ping within `worker_inactive_threshold`
+    pub fn active_workers_count(&self) -> usize {
+        self.last_pings
+            .keys()
+            .filter(|worker_id| self.worker_active(worker_id))
+            .count()
+    }
+
     fn worker_active(&self, worker_id: &PeerId) -> bool {
         let inactive_threshold = Config::get().worker_inactive_threshold;
         self.last_pings
@@ -127,22 +472,99 @@ impl NetworkState {
             .is_some_and(|t| *t + inactive_threshold > Instant::now())
     }
 
+    /// Greylisted manually, by an open circuit breaker, or half-open and running
+    /// as many trial queries as allowed
     fn worker_greylisted(&self, worker_id: &PeerId) -> bool {
-        let greylist_time = Config::get().worker_greylist_time;
-        self.worker_greylist
-            .get(worker_id)
-            .is_some_and(|t| *t + greylist_time > Instant::now())
+        if self.worker_override(worker_id) == Some(OverrideKind::Greylist) {
+            return true;
+        }
+        let Some(breaker) = self.circuit_breakers.get(worker_id) else {
+            return false;
+        };
+        match breaker.state() {
+            BreakerState::Closed => false,
+            BreakerState::Open => true,
+            BreakerState::HalfOpen => breaker.trials_exhausted(&Config::get().circuit_breaker),
+        }
     }
 
     pub fn greylisted_workers(&self) -> Vec<PeerId> {
-        let greylist_time = Config::get().worker_greylist_time;
-        let now = Instant::now();
-        self.worker_greylist
+        let manually_greylisted = self
+            .worker_overrides()
+            .into_iter()
+            .filter(|o| o.kind == OverrideKind::Greylist)
+            .map(|o| o.worker_id);
+        self.circuit_breakers
             .iter()
-            .filter_map(|(worker_id, t)| (*t + greylist_time > now).then_some(*worker_id))
+            .filter_map(|(worker_id, breaker)| {
+                (breaker.state() == BreakerState::Open).then_some(*worker_id)
+            })
+            .chain(manually_greylisted)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    fn worker_override(&self, worker_id: &PeerId) -> Option<OverrideKind> {
+        self.worker_overrides
+            .get(worker_id)
+            .filter(|o| !o.expired())
+            .map(|o| o.kind)
+    }
+
+    /// All manual overrides which haven't expired yet
+    pub fn worker_overrides(&self) -> Vec<WorkerOverride> {
+        self.worker_overrides
+            .values()
+            .filter(|o| !o.expired())
+            .cloned()
             .collect()
     }
 
+    pub fn set_worker_override(&mut self, worker_override: WorkerOverride) {
+        log::info!(
+            "Worker {} manually set to {:?} by {}: {}",
+            worker_override.worker_id,
+            worker_override.kind,
+            worker_override.set_by,
+            worker_override.reason
+        );
+        self.worker_overrides
+            .insert(worker_override.worker_id, worker_override);
+    }
+
+    /// Drop manual overrides which have expired
+    pub fn purge_expired_overrides(&mut self) {
+        self.worker_overrides.retain(|worker_id, o| {
+            let expired = o.expired();
+            if expired {
+                log::info!("Override of worker {worker_id} has expired");
+            }
+            !expired
+        });
+    }
+
+    /// Lift manual override and automatic greylisting of the worker.
+    /// Returns `false` if the worker had neither an override nor a circuit breaker.
+    pub fn restore_worker(&mut self, worker_id: &PeerId) -> bool {
+        log::info!("Restoring worker {worker_id}");
+        let had_override = self.worker_overrides.remove(worker_id).is_some();
+        let Some(breaker) = self.circuit_breakers.get_mut(worker_id) else {
+            return had_override;
+        };
+        breaker.reset();
+        metrics::breaker_state_changed(&worker_id.to_string(), BreakerState::Closed);
+        true
+    }
+
+    pub fn chain_updated(&mut self) {
+        self.last_chain_update = Some(SystemTime::now());
+    }
+
+    pub fn last_chain_update(&self) -> Option<SystemTime> {
+        self.last_chain_update
+    }
+
     pub fn reset_allocations_cache(&mut self) {
         self.workers_without_allocation.clear();
     }
@@ -155,21 +577,101 @@ impl NetworkState {
         !self.workers_without_allocation.contains(worker_id)
     }
 
+    /// Counts the query as in flight, and as a trial if the worker's circuit breaker is half-open
+    pub fn task_started(&mut self, worker_id: PeerId, query_id: &str) {
+        let in_flight = self.in_flight.entry(worker_id).or_default();
+        *in_flight += 1;
+        metrics::set_in_flight(&worker_id.to_string(), *in_flight);
+        if let Some(breaker) = self.circuit_breakers.get_mut(&worker_id) {
+            if breaker.query_started(query_id, &Config::get().circuit_breaker) {
+                log::debug!("Query {query_id} is a trial for worker {worker_id}");
+            }
+        }
+    }
+
+    /// Has to be called after the outcome of the query has been recorded
+    pub fn task_finished(&mut self, worker_id: PeerId, query_id: &str) {
+        if let Some(breaker) = self.circuit_breakers.get_mut(&worker_id) {
+            breaker.query_finished(query_id);
+        }
+        let in_flight = match self.in_flight.get_mut(&worker_id) {
+            Some(in_flight) => {
+                *in_flight = in_flight.saturating_sub(1);
+                *in_flight
+            }
+            None => 0,
+        };
+        if in_flight == 0 {
+            self.in_flight.remove(&worker_id);
+        }
+        metrics::set_in_flight(&worker_id.to_string(), in_flight);
+    }
+
+    pub fn in_flight(&self, worker_id: &PeerId) -> usize {
+        self.in_flight.get(worker_id).copied().unwrap_or_default()
+    }
+
+    /// Whether the worker runs as many queries as allowed by `max_in_flight_per_worker`
+    pub fn worker_saturated(&self, worker_id: &PeerId) -> bool {
+        Config::get()
+            .worker_selection
+            .max_in_flight_per_worker
+            .is_some_and(|limit| self.in_flight(worker_id) >= limit)
+    }
+
+    pub fn update_remaining_cus(&mut self, remaining_cus: Vec<(PeerId, u32)>) {
+        self.remaining_cus = remaining_cus.into_iter().collect();
+    }
+
+    pub fn cus_spent(&mut self, worker_id: PeerId, cus: u32) {
+        if let Some(remaining) = self.remaining_cus.get_mut(&worker_id) {
+            *remaining = remaining.saturating_sub(cus);
+        }
+    }
+
     pub fn update_dataset_states(
         &mut self,
         worker_id: PeerId,
         mut worker_state: HashMap<DatasetId, RangeSet>,
     ) {
         self.last_pings.insert(worker_id, Instant::now());
-        for dataset_id in Config::get().available_datasets.values() {
-            let dataset_state = worker_state
+        for (dataset, dataset_id) in Config::get().available_datasets.iter() {
+            let range_set = worker_state
                 .remove(dataset_id)
                 .unwrap_or_else(RangeSet::empty);
-            self.dataset_states
-                .entry(dataset_id.clone())
-                .or_default()
-                .update(worker_id, dataset_state);
+            let dataset_state = self.dataset_states.entry(dataset_id.clone()).or_default();
+            if dataset_state.update(worker_id, range_set) {
+                let height = dataset_state.height();
+                self.publish_height(dataset, dataset_id, height);
+            }
+        }
+    }
+
+    fn publish_height(&mut self, dataset: &str, dataset_id: &DatasetId, height: DatasetHeight) {
+        let last_sent = self
+            .height_updates
+            .last_sent
+            .insert(dataset_id.clone(), height);
+        if last_sent == Some(height) {
+            return;
         }
+        // Sending only fails if there are no subscribers
+        let _ = self.height_updates.sender.send(HeightUpdate {
+            dataset: dataset.to_string(),
+            height,
+        });
+    }
+
+    /// Subscribe to changes of heights of all datasets
+    pub fn subscribe_heights(&self) -> broadcast::Receiver<HeightUpdate> {
+        self.height_updates.sender.subscribe()
+    }
+
+    pub fn dataset_height(&self, dataset_id: &DatasetId) -> DatasetHeight {
+        self.dataset_states
+            .get(dataset_id)
+            .map(DatasetState::height)
+            .unwrap_or_default()
     }
 
     pub fn update_registered_workers(&mut self, workers: Vec<Worker>) {
@@ -177,9 +679,95 @@ impl NetworkState {
         self.registered_workers = workers.into_iter().map(|w| w.peer_id).collect();
     }
 
-    pub fn greylist_worker(&mut self, worker_id: PeerId) {
-        log::info!("Grey-listing worker {worker_id}");
-        self.worker_greylist.insert(worker_id, Instant::now());
+    /// Records the execution time of successful and timed out queries. Timeouts count as
+    /// at least `default_query_timeout`, because clients may set much shorter timeouts.
+    /// Successful queries close the worker's circuit breaker if it's half-open.
+    pub fn query_finished(&mut self, task: &FinishedTask) {
+        match task.result {
+            QueryResult::Ok(_) => self.observe_latency(task.worker_id, task.exec_time),
+            QueryResult::Timeout(_) => {
+                let penalty = task.exec_time.max(Config::get().default_query_timeout);
+                self.observe_latency(task.worker_id, penalty);
+            }
+            _ => {}
+        }
+        if let QueryResult::Ok(_) = task.result {
+            let config = &Config::get().circuit_breaker;
+            let worker_id = task.worker_id;
+            if let Some(breaker) = self.circuit_breakers.get_mut(&worker_id) {
+                if breaker.success(&task.query_id, config) {
+                    log::info!("Worker {worker_id} restored after successful trial queries");
+                    metrics::breaker_state_changed(&worker_id.to_string(), BreakerState::Closed);
+                }
+            }
+        }
+    }
+
+    fn observe_latency(&mut self, worker_id: PeerId, exec_time: Duration) {
+        let alpha = Config::get().worker_selection.latency_ewma_alpha;
+        self.worker_latency
+            .entry(worker_id)
+            .or_default()
+            .observe(exec_time, alpha);
+    }
+
+    /// Latency statistics of all workers which have executed queries, fastest first
+    pub fn worker_latencies(&self) -> Vec<LatencySummary> {
+        let mut latencies: Vec<_> = self.worker_latency.iter().collect();
+        latencies.sort_by_key(|(_, latency)| latency.ewma());
+        latencies
+            .into_iter()
+            .map(|(worker_id, latency)| latency.summary(*worker_id))
+            .collect()
+    }
+
+    /// Opens the worker's circuit breaker, greylisting it for longer after each failure in a row
+    pub fn worker_failed(&mut self, worker_id: PeerId, query_id: &str, failure: Failure) {
+        let config = &Config::get().circuit_breaker;
+        let breaker = self.circuit_breakers.entry(worker_id).or_default();
+        if let Some(open_time) = breaker.failure(query_id, failure, config) {
+            log::info!(
+                "Grey-listing worker {worker_id} for {}s after {failure:?}",
+                open_time.as_secs()
+            );
+            metrics::breaker_state_changed(&worker_id.to_string(), BreakerState::Open);
+        }
+    }
+
+    /// Everything the gateway knows about the worker, `None` if it has never been seen
+    pub fn worker_info(&self, worker_id: &PeerId) -> Option<WorkerInfo> {
+        let known = self.registered_workers.contains(worker_id)
+            || self.last_pings.contains_key(worker_id)
+            || self.worker_overrides.contains_key(worker_id)
+            || self.worker_latency.contains_key(worker_id)
+            || self.circuit_breakers.contains_key(worker_id);
+        if !known {
+            return None;
+        }
+        Some(WorkerInfo {
+            worker_id: worker_id.to_string(),
+            registered: self.registered_workers.contains(worker_id),
+            active: self.worker_active(worker_id),
+            worker_override: self.worker_override(worker_id),
+            remaining_cus: self.remaining_cus.get(worker_id).copied(),
+            in_flight: self.in_flight(worker_id),
+            latency: self
+                .worker_latency
+                .get(worker_id)
+                .map(|latency| latency.summary(*worker_id)),
+            circuit_breaker: self
+                .circuit_breakers
+                .get(worker_id)
+                .map(CircuitBreaker::summary)
+                .unwrap_or_else(|| CircuitBreaker::default().summary()),
+        })
+    }
+
+    /// Updates the state gauges, which may have changed from open to half-open as time passed
+    pub fn update_breaker_metrics(&self) {
+        for (worker_id, breaker) in &self.circuit_breakers {
+            metrics::set_breaker_state(&worker_id.to_string(), breaker.state());
+        }
     }
 
     pub fn get_height(&self, dataset_id: &DatasetId) -> Option<u32> {
@@ -188,6 +776,17 @@ impl NetworkState {
             .map(|state| state.highest_indexable_block())
     }
 
+    pub fn worker_range_end(
+        &self,
+        dataset_id: &DatasetId,
+        worker_id: &PeerId,
+        block: u32,
+    ) -> Option<u32> {
+        self.dataset_states
+            .get(dataset_id)?
+            .worker_range_end(worker_id, block)
+    }
+
     pub fn summary(&self) -> impl Iterator<Item = DatasetSummary> {
         Config::get()
             .available_datasets
@@ -195,7 +794,281 @@ impl NetworkState {
             .map(|(name, id)| DatasetSummary::new(name, self.dataset_states.get(id)))
     }
 
+    pub fn datasets(&self) -> Vec<DatasetInfo> {
+        let mut datasets: Vec<DatasetInfo> = Config::get()
+            .available_datasets
+            .iter()
+            .map(|(name, id)| self.dataset_info(name, id))
+            .collect();
+        datasets.sort_by(|a, b| a.name.cmp(&b.name));
+        datasets
+    }
+
+    pub fn dataset_info(&self, name: &str, dataset_id: &DatasetId) -> DatasetInfo {
+        let state = self.dataset_states.get(dataset_id);
+        let workers: Vec<&PeerId> = state
+            .map(|state| {
+                state
+                    .worker_ranges
+                    .iter()
+                    .filter_map(|(peer_id, range_set)| {
+                        (!range_set.ranges.is_empty()).then_some(peer_id)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        DatasetInfo {
+            name: name.to_string(),
+            id: dataset_id.clone(),
+            url: dataset_id.to_url(),
+            highest_indexable_block: state.map_or(0, |s| s.highest_indexable_block()),
+            highest_seen_block: state.map_or(0, |s| s.highest_seen_block),
+            active_workers: workers.iter().filter(|w| self.worker_active(w)).count(),
+            available_workers: workers
+                .iter()
+                .filter(|w| self.worker_available(w, false))
+                .count(),
+            last_range_update: state
+                .and_then(|s| s.last_range_update)
+                .map(|t| DateTime::<Utc>::from(t).to_rfc3339()),
+        }
+    }
+
     pub fn network_state(&self) -> HashMap<DatasetId, DatasetState> {
         self.dataset_states.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    const SAMPLES: usize = 10_000;
+
+    fn balancing_config() -> WorkerSelectionConfig {
+        WorkerSelectionConfig {
+            balance_allocations: true,
+            range_policy: RangePolicy::Ignore,
+            ..Default::default()
+        }
+    }
+
+    /// Number of times each candidate has been sampled
+    fn sample_counts(
+        state: &NetworkState,
+        candidates: &[(PeerId, u32)],
+        start_block: u32,
+        config: &WorkerSelectionConfig,
+    ) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = vec![0; candidates.len()];
+        for _ in 0..SAMPLES {
+            let worker_id = state
+                .sample_worker(candidates, start_block, config, &mut rng)
+                .unwrap();
+            let index = candidates
+                .iter()
+                .position(|(candidate, _)| *candidate == worker_id)
+                .unwrap();
+            counts[index] += 1;
+        }
+        counts
+    }
+
+    fn workers(n: usize) -> Vec<(PeerId, u32)> {
+        (0..n).map(|_| (PeerId::random(), 100)).collect()
+    }
+
+    fn latency_config() -> WorkerSelectionConfig {
+        WorkerSelectionConfig {
+            strategy: WorkerSelectionStrategy::Latency,
+            exploration_rate: 0.0,
+            balance_allocations: false,
+            range_policy: RangePolicy::Ignore,
+            ..Default::default()
+        }
+    }
+
+    /// Number of times each candidate has been chosen
+    fn choice_counts(
+        state: &NetworkState,
+        candidates: &[(PeerId, u32)],
+        start_block: u32,
+        config: &WorkerSelectionConfig,
+    ) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = vec![0; candidates.len()];
+        for _ in 0..SAMPLES {
+            let worker_id = state
+                .choose_worker(candidates, start_block, config, &mut rng)
+                .unwrap();
+            let index = candidates
+                .iter()
+                .position(|(candidate, _)| *candidate == worker_id)
+                .unwrap();
+            counts[index] += 1;
+        }
+        counts
+    }
+
+    fn observe(state: &mut NetworkState, worker_id: PeerId, millis: u64) {
+        state
+            .worker_latency
+            .entry(worker_id)
+            .or_default()
+            .observe(Duration::from_millis(millis), 0.5);
+    }
+
+    #[test]
+    fn faster_of_two_chosen() {
+        let candidates = workers(2);
+        let mut state = NetworkState::default();
+        observe(&mut state, candidates[0].0, 1000);
+        observe(&mut state, candidates[1].0, 100);
+        let counts = choice_counts(&state, &candidates, 0, &latency_config());
+        assert_eq!(counts, vec![0, SAMPLES]);
+    }
+
+    #[test]
+    fn load_taken_into_account() {
+        let candidates = workers(2);
+        let mut state = NetworkState::default();
+        observe(&mut state, candidates[0].0, 1000);
+        observe(&mut state, candidates[1].0, 100);
+        // Finishing 20 queries of 100ms takes longer than one of 1s
+        state.in_flight.insert(candidates[1].0, 20);
+        let counts = choice_counts(&state, &candidates, 0, &latency_config());
+        assert_eq!(counts, vec![SAMPLES, 0]);
+    }
+
+    #[test]
+    fn unmeasured_workers_preferred() {
+        let candidates = workers(2);
+        let mut state = NetworkState::default();
+        observe(&mut state, candidates[0].0, 10);
+        let counts = choice_counts(&state, &candidates, 0, &latency_config());
+        assert_eq!(counts, vec![0, SAMPLES]);
+    }
+
+    #[test]
+    fn exploration_ignores_latency() {
+        let candidates = workers(2);
+        let mut state = NetworkState::default();
+        observe(&mut state, candidates[0].0, 1000);
+        observe(&mut state, candidates[1].0, 100);
+        let config = WorkerSelectionConfig {
+            exploration_rate: 1.0,
+            ..latency_config()
+        };
+        let counts = choice_counts(&state, &candidates, 0, &config);
+        assert!((4500..5500).contains(&counts[0]), "{counts:?}");
+    }
+
+    #[test]
+    fn p2c_spreads_load_over_fast_workers() {
+        let candidates = workers(3);
+        let mut state = NetworkState::default();
+        for (worker_id, _) in &candidates {
+            observe(&mut state, *worker_id, 100);
+        }
+        // Two different workers are compared, so the slowest one always loses
+        observe(&mut state, candidates[2].0, 10_000);
+        let counts = choice_counts(&state, &candidates, 0, &latency_config());
+        assert_eq!(counts[2], 0);
+        assert!((4500..5500).contains(&counts[0]), "{counts:?}");
+    }
+
+    #[test]
+    fn sampling_weighted_by_remaining_cus() {
+        let candidates = workers(3);
+        let mut state = NetworkState::default();
+        state.update_remaining_cus(vec![
+            (candidates[0].0, 100),
+            (candidates[1].0, 300),
+            (candidates[2].0, 0),
+        ]);
+        let counts = sample_counts(&state, &candidates, 0, &balancing_config());
+        assert!((2000..3000).contains(&counts[0]), "{counts:?}");
+        assert!((7000..8000).contains(&counts[1]), "{counts:?}");
+        assert_eq!(counts[2], 0);
+    }
+
+    #[test]
+    fn unknown_allocations_get_mean_weight() {
+        let candidates = workers(3);
+        let mut state = NetworkState::default();
+        state.update_remaining_cus(vec![(candidates[0].0, 100), (candidates[1].0, 300)]);
+        let counts = sample_counts(&state, &candidates, 0, &balancing_config());
+        // Weights are 100, 300 and 200
+        assert!((1300..2000).contains(&counts[0]), "{counts:?}");
+        assert!((4600..5400).contains(&counts[1]), "{counts:?}");
+        assert!((3000..3700).contains(&counts[2]), "{counts:?}");
+    }
+
+    #[test]
+    fn exhausted_allocations_sampled_uniformly() {
+        let candidates = workers(2);
+        let mut state = NetworkState::default();
+        state.update_remaining_cus(vec![(candidates[0].0, 0), (candidates[1].0, 0)]);
+        let counts = sample_counts(&state, &candidates, 0, &balancing_config());
+        assert!((4500..5500).contains(&counts[0]), "{counts:?}");
+    }
+
+    fn range_config(range_policy: RangePolicy) -> WorkerSelectionConfig {
+        WorkerSelectionConfig {
+            strategy: WorkerSelectionStrategy::Random,
+            balance_allocations: false,
+            range_policy,
+            ..Default::default()
+        }
+    }
+
+    /// Ranges starting at block 1000 are 100 and 300 blocks long
+    fn ranged_workers() -> Vec<(PeerId, u32)> {
+        vec![(PeerId::random(), 1099), (PeerId::random(), 1299)]
+    }
+
+    #[test]
+    fn longer_ranges_preferred() {
+        let candidates = ranged_workers();
+        let state = NetworkState::default();
+        let config = range_config(RangePolicy::Prefer);
+        let counts = choice_counts(&state, &candidates, 1000, &config);
+        assert!((2000..3000).contains(&counts[0]), "{counts:?}");
+    }
+
+    #[test]
+    fn furthest_range_required() {
+        let candidates = ranged_workers();
+        let state = NetworkState::default();
+        let config = range_config(RangePolicy::Require);
+        let counts = choice_counts(&state, &candidates, 1000, &config);
+        assert_eq!(counts, vec![0, SAMPLES]);
+    }
+
+    #[test]
+    fn ranges_ignored() {
+        let candidates = ranged_workers();
+        let state = NetworkState::default();
+        let config = range_config(RangePolicy::Ignore);
+        let counts = choice_counts(&state, &candidates, 1000, &config);
+        assert!((4500..5500).contains(&counts[0]), "{counts:?}");
+    }
+
+    #[test]
+    fn range_and_allocation_weights_combined() {
+        let candidates = ranged_workers();
+        let mut state = NetworkState::default();
+        // The longer range is offset by fewer compute units left
+        state.update_remaining_cus(vec![(candidates[0].0, 300), (candidates[1].0, 100)]);
+        let config = WorkerSelectionConfig {
+            balance_allocations: true,
+            ..range_config(RangePolicy::Prefer)
+        };
+        let counts = choice_counts(&state, &candidates, 1000, &config);
+        assert!((4500..5500).contains(&counts[0]), "{counts:?}");
+    }
+}
@@ -3,22 +3,69 @@ use std::time::Duration;
 
 use axum::http::StatusCode;
 use derivative::Derivative;
+use serde::Deserialize;
 use tokio::sync::oneshot;
 
 use subsquid_messages::{query_result, OkResult};
 use subsquid_network_transport::PeerId;
 
+use crate::api_error::ErrorCode;
 use crate::config::DatasetId;
 
+/// Query ID generated by the gateway, linked to the request ID sent by the client, if any.
+/// Displayed together, so that all log lines for the query contain both.
+#[derive(Debug, Clone)]
+pub struct QueryId {
+    pub id: String,
+    pub request_id: Option<String>,
+}
+
+impl QueryId {
+    pub fn generate(request_id: Option<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            request_id,
+        }
+    }
+}
+
+impl Display for QueryId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.request_id {
+            Some(request_id) => write!(f, "{} (request {request_id})", self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
 #[derive(Derivative, Debug)]
 pub struct Query {
+    pub query_id: QueryId,
     pub dataset_id: DatasetId,
     pub query: String,
     pub worker_id: PeerId,
     pub timeout: Duration,
     pub profiling: bool,
     #[derivative(Debug = "ignore")]
-    pub result_sender: oneshot::Sender<QueryResult>,
+    pub result_sender: oneshot::Sender<QueryResponse>,
+}
+
+/// Query result with the gateway's measurements
+#[derive(Debug)]
+pub struct QueryResponse {
+    pub result: QueryResult,
+    /// Time between sending the query and getting the result.
+    /// `None` if the query has been rejected before being sent to the worker.
+    pub exec_time: Option<Duration>,
+}
+
+impl From<QueryResult> for QueryResponse {
+    fn from(result: QueryResult) -> Self {
+        Self {
+            result,
+            exec_time: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -65,4 +112,48 @@ impl QueryResult {
             QueryResult::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
         }
     }
+
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        match self {
+            QueryResult::Ok(_) => None,
+            QueryResult::BadRequest(_) => Some(ErrorCode::BadRequest),
+            QueryResult::ServerError(_) => Some(ErrorCode::WorkerServerError),
+            QueryResult::NoAllocation => Some(ErrorCode::NoAllocation),
+            QueryResult::Timeout(_) => Some(ErrorCode::WorkerTimeout),
+        }
+    }
+}
+
+/// Query could not be executed by the gateway
+#[derive(Debug, Clone, Copy)]
+pub enum QueryError {
+    /// Too many queries waiting to be sent
+    QueueFull,
+    /// Query has been dropped before the result arrived
+    Dropped,
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryError::QueueFull => write!(f, "Cannot send query"),
+            QueryError::Dropped => write!(f, "Query dropped"),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Block range of a query. The rest of the query is opaque to the gateway.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRange {
+    pub from_block: u32,
+    pub to_block: Option<u32>,
+}
+
+impl QueryRange {
+    pub fn parse(query: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(query)?)
+    }
 }
@@ -0,0 +1,160 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::Stream;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::{Map, Value};
+
+use subsquid_messages::OkResult;
+
+use crate::client::{QueryClient, RoutedQueryResult};
+use crate::config::DatasetId;
+use crate::encoding::decode_gzip;
+use crate::query::QueryResult;
+
+#[derive(Deserialize)]
+struct Block {
+    header: BlockHeader,
+}
+
+#[derive(Deserialize)]
+struct BlockHeader {
+    number: u32,
+}
+
+/// Executes a query over the whole `fromBlock..toBlock` range, one worker chunk at a time.
+/// Every partial result is emitted as soon as it arrives, one block per line.
+pub struct RangeStream {
+    client: Arc<QueryClient>,
+    dataset_id: DatasetId,
+    query: Map<String, Value>,
+    next_block: u32,
+    to_block: Option<u32>,
+    timeout: Option<Duration>,
+    profiling: bool,
+    /// Sent by the client, linked to the IDs of all queries
+    request_id: Option<String>,
+    finished: bool,
+}
+
+impl RangeStream {
+    pub fn new(
+        client: Arc<QueryClient>,
+        dataset_id: DatasetId,
+        query: Map<String, Value>,
+        from_block: u32,
+        to_block: Option<u32>,
+        timeout: Option<Duration>,
+        profiling: bool,
+    ) -> Self {
+        Self {
+            client,
+            dataset_id,
+            query,
+            next_block: from_block,
+            to_block,
+            timeout,
+            profiling,
+            request_id: None,
+            finished: false,
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: Option<String>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<Vec<u8>, Infallible>> {
+        futures::stream::unfold(self, |mut state| async move {
+            if state.finished {
+                return None;
+            }
+            match state.next_chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), state)),
+                Ok(None) => None,
+                Err(err) => {
+                    log::warn!(
+                        "Range stream for dataset {} (request {}) failed: {err:?}",
+                        state.dataset_id,
+                        state.request_id.as_deref().unwrap_or("-")
+                    );
+                    state.finished = true;
+                    let line = serde_json::json!({ "error": err.to_string() });
+                    Some((Ok(format!("{line}\n").into_bytes()), state))
+                }
+            }
+        })
+    }
+
+    async fn next_chunk(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let from_block = self.next_block;
+        let height = self
+            .client
+            .get_height(&self.dataset_id)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Height of dataset {} is unknown", self.dataset_id))?;
+        let last_block = self
+            .to_block
+            .map_or(height, |to_block| to_block.min(height));
+        if from_block > last_block {
+            return Ok(None);
+        }
+
+        self.query
+            .insert("fromBlock".to_string(), Value::from(from_block));
+        let query = serde_json::to_string(&self.query)?;
+        let RoutedQueryResult {
+            query_id,
+            result,
+            tried_workers,
+            ..
+        } = self
+            .client
+            .execute_routed_query(
+                self.request_id.clone(),
+                self.dataset_id.clone(),
+                query,
+                from_block,
+                self.timeout,
+                self.profiling,
+            )
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No available worker for block {from_block}"))?;
+        let data = match result {
+            QueryResult::Ok(OkResult { data, .. }) => decode_gzip(data)?,
+            res => anyhow::bail!("Query {query_id} from block {from_block} failed: {res}"),
+        };
+
+        // The worker only returns data up to the end of its chunk (or less, if the result
+        // is too big), so the next query has to start right after the last returned block.
+        let blocks: Vec<Box<RawValue>> = serde_json::from_slice(&data)?;
+        let chunk_end = match blocks.last() {
+            Some(block) => serde_json::from_str::<Block>(block.get())?.header.number,
+            None => {
+                let worker_id = tried_workers.last().expect("Query was sent to a worker");
+                self.client
+                    .worker_range_end(&self.dataset_id, worker_id, from_block)
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("Worker {worker_id} range not found"))?
+            }
+        };
+        anyhow::ensure!(
+            chunk_end >= from_block,
+            "Query from block {from_block} returned block {chunk_end}"
+        );
+        match chunk_end.checked_add(1) {
+            Some(next_block) => self.next_block = next_block,
+            None => self.finished = true,
+        }
+
+        let mut chunk = Vec::with_capacity(data.len());
+        for block in blocks {
+            chunk.extend_from_slice(block.get().as_bytes());
+            chunk.push(b'\n');
+        }
+        Ok(Some(chunk))
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http_body::{Frame, SizeHint};
+
+use crate::api_error::{ApiError, ErrorCode};
+use crate::auth::ClientId;
+use crate::config::{Config, RateLimit};
+use crate::metrics;
+
+/// Idle clients are forgotten once this many clients are tracked
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ClientKey {
+    ApiKey(String),
+    Ip(IpAddr),
+}
+
+impl ClientKey {
+    /// Label used in metrics. IP addresses are not used to keep the cardinality low.
+    fn metrics_label(&self) -> &str {
+        match self {
+            ClientKey::ApiKey(id) => id,
+            ClientKey::Ip(_) => "anonymous",
+        }
+    }
+}
+
+impl Display for ClientKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientKey::ApiKey(id) => write!(f, "key {id}"),
+            ClientKey::Ip(ip) => write!(f, "IP {ip}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClientState {
+    /// Limit of this client, used to refill the bucket when other clients are cleaned up
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+    in_flight: u32,
+}
+
+impl ClientState {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+            in_flight: 0,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.limit.requests_per_sec).min(self.limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    fn idle(&self) -> bool {
+        self.in_flight == 0 && self.tokens >= self.limit.burst as f64
+    }
+
+    /// Takes `requests` tokens at once, or none if there are not enough
+    fn take(&mut self, requests: u32) -> Result<(), Throttled> {
+        let requests = requests as f64;
+        if self.tokens < requests {
+            let wait_secs = (requests - self.tokens) / self.limit.requests_per_sec;
+            return Err(Throttled::Rate(wait_secs.ceil().max(1.0) as u64));
+        }
+        self.tokens -= requests;
+        Ok(())
+    }
+}
+
+enum Throttled {
+    /// Request rate exceeded. Contains the number of seconds until the next request is allowed.
+    Rate(u64),
+    Concurrency,
+}
+
+impl Throttled {
+    fn reason(&self) -> &'static str {
+        match self {
+            Throttled::Rate(_) => "rate",
+            Throttled::Concurrency => "concurrency",
+        }
+    }
+
+    fn retry_after_secs(&self) -> u64 {
+        match self {
+            Throttled::Rate(secs) => *secs,
+            Throttled::Concurrency => 1,
+        }
+    }
+}
+
+/// Per-client token bucket rate limiter with a cap on concurrent requests
+#[derive(Default)]
+pub struct RateLimiter {
+    clients: Mutex<HashMap<ClientKey, ClientState>>,
+}
+
+impl RateLimiter {
+    fn try_acquire(
+        self: &Arc<Self>,
+        client: ClientKey,
+        limit: RateLimit,
+    ) -> Result<InFlightGuard, Throttled> {
+        let mut clients = self.clients.lock().expect("Rate limiter lock poisoned");
+        if clients.len() >= MAX_TRACKED_CLIENTS {
+            clients.retain(|_, state| {
+                state.refill();
+                !state.idle()
+            });
+        }
+
+        let state = clients
+            .entry(client.clone())
+            .or_insert_with(|| ClientState::new(limit));
+        state.limit = limit;
+        state.refill();
+        if limit
+            .max_in_flight
+            .is_some_and(|max_in_flight| state.in_flight >= max_in_flight)
+        {
+            return Err(Throttled::Concurrency);
+        }
+        state.take(1)?;
+        state.in_flight += 1;
+
+        Ok(InFlightGuard {
+            limiter: self.clone(),
+            client,
+            slots: 1,
+        })
+    }
+
+    /// Takes `requests` more tokens from a client whose request is in flight, and up to
+    /// `in_flight` more in-flight slots, depending on how many are free
+    fn try_charge(
+        self: &Arc<Self>,
+        client: &ClientKey,
+        requests: u32,
+        in_flight: u32,
+    ) -> Result<InFlightGuard, Throttled> {
+        let mut clients = self.clients.lock().expect("Rate limiter lock poisoned");
+        let slots = match clients.get_mut(client) {
+            Some(state) => {
+                state.refill();
+                state.take(requests)?;
+                let free = state
+                    .limit
+                    .max_in_flight
+                    .map_or(in_flight, |max| max.saturating_sub(state.in_flight));
+                let slots = in_flight.min(free);
+                state.in_flight += slots;
+                slots
+            }
+            // Can only happen if the client has been cleaned up while its request is in flight
+            None => 0,
+        };
+        Ok(InFlightGuard {
+            limiter: self.clone(),
+            client: client.clone(),
+            slots,
+        })
+    }
+
+    fn release(&self, client: &ClientKey, slots: u32) {
+        let mut clients = self.clients.lock().expect("Rate limiter lock poisoned");
+        if let Some(state) = clients.get_mut(client) {
+            state.in_flight = state.in_flight.saturating_sub(slots);
+        }
+    }
+
+    fn burst(&self, client: &ClientKey) -> Option<u32> {
+        let clients = self.clients.lock().expect("Rate limiter lock poisoned");
+        clients.get(client).map(|state| state.limit.burst)
+    }
+}
+
+/// Marks the request as no longer in flight when dropped
+pub struct InFlightGuard {
+    limiter: Arc<RateLimiter>,
+    client: ClientKey,
+    /// Number of in-flight slots taken
+    slots: u32,
+}
+
+impl InFlightGuard {
+    pub fn slots(&self) -> u32 {
+        self.slots
+    }
+
+    /// Keeps the slots taken until the body has been sent or dropped
+    pub fn hold_until_sent(self, body: Body) -> Body {
+        Body::new(GuardedBody {
+            inner: body,
+            guard: Some(self),
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.limiter.release(&self.client, self.slots);
+    }
+}
+
+/// Lets handlers charge the client for more than one request,
+/// e.g. for every query of a batch. Only added for rate-limited clients.
+#[derive(Clone)]
+pub struct ClientQuota {
+    limiter: Arc<RateLimiter>,
+    client: ClientKey,
+}
+
+impl ClientQuota {
+    /// Charges the client for `requests` more requests and takes up to `in_flight` more
+    /// in-flight slots, depending on how many are free
+    pub fn charge(&self, requests: u32, in_flight: u32) -> Result<InFlightGuard, ApiError> {
+        if let Some(burst) = self
+            .limiter
+            .burst(&self.client)
+            .filter(|burst| requests >= *burst)
+        {
+            return Err(ApiError::bad_request(format!(
+                "Request counts as {} requests, more than the burst of {burst} allowed",
+                requests.saturating_add(1)
+            )));
+        }
+        self.limiter
+            .try_charge(&self.client, requests, in_flight)
+            .map_err(|throttled| throttled_error(&self.client, throttled))
+    }
+}
+
+/// Response body keeping the request in flight until the body has been sent or dropped,
+/// so that streaming responses count towards `max_in_flight`
+struct GuardedBody {
+    inner: Body,
+    guard: Option<InFlightGuard>,
+}
+
+impl HttpBody for GuardedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(None) = poll {
+            self.guard.take();
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Middleware rejecting requests over the client's rate limit with `429 Too Many Requests`.
+/// Has to run after authentication, so that clients can be identified by API key.
+pub async fn throttle(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let key_id = req.extensions().get::<ClientId>().map(|ClientId(id)| id);
+    let limit = match Config::get().rate_limit(key_id.map(String::as_str)) {
+        Some(limit) => limit,
+        None => return next.run(req).await,
+    };
+    let client = match key_id {
+        Some(id) => ClientKey::ApiKey(id.clone()),
+        None => ClientKey::Ip(addr.ip()),
+    };
+
+    let guard = match limiter.try_acquire(client.clone(), limit) {
+        Ok(guard) => guard,
+        Err(throttled) => return throttled_error(&client, throttled).into_response(),
+    };
+    req.extensions_mut().insert(ClientQuota {
+        limiter: limiter.clone(),
+        client,
+    });
+    next.run(req).await.map(|body| guard.hold_until_sent(body))
+}
+
+fn throttled_error(client: &ClientKey, throttled: Throttled) -> ApiError {
+    log::debug!("Throttling request from {client}: {}", throttled.reason());
+    metrics::request_throttled(client.metrics_label(), throttled.reason());
+    ApiError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::TooManyRequests,
+        format!("Too many requests from {client}"),
+    )
+    .with_retry_after(throttled.retry_after_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limit(max_in_flight: Option<u32>) -> RateLimit {
+        RateLimit {
+            requests_per_sec: 10.0,
+            burst: 5,
+            max_in_flight,
+        }
+    }
+
+    fn client() -> ClientKey {
+        ClientKey::ApiKey("test".to_string())
+    }
+
+    fn in_flight(limiter: &RateLimiter) -> u32 {
+        let clients = limiter.clients.lock().unwrap();
+        clients.get(&client()).map_or(0, |state| state.in_flight)
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut state = ClientState::new(limit(None));
+        assert!(state.take(5).is_ok());
+        assert!(matches!(state.take(1), Err(Throttled::Rate(1))));
+
+        state.last_refill -= Duration::from_millis(250);
+        state.refill();
+        assert!(state.take(2).is_ok());
+        assert!(state.take(1).is_err());
+
+        // Never grows over the burst
+        state.last_refill -= Duration::from_secs(60);
+        state.refill();
+        assert_eq!(state.tokens, 5.0);
+        assert!(state.idle());
+    }
+
+    #[test]
+    fn retry_after_covers_missing_tokens() {
+        let mut state = ClientState::new(limit(None));
+        state.tokens = 0.0;
+        assert!(matches!(state.take(25), Err(Throttled::Rate(3))));
+        // Nothing is taken if there are not enough tokens
+        assert_eq!(state.tokens, 0.0);
+    }
+
+    #[test]
+    fn in_flight_limited() {
+        let limiter = Arc::new(RateLimiter::default());
+        let first = limiter.try_acquire(client(), limit(Some(1))).ok().unwrap();
+        assert!(matches!(
+            limiter.try_acquire(client(), limit(Some(1))),
+            Err(Throttled::Concurrency)
+        ));
+        drop(first);
+        assert!(limiter.try_acquire(client(), limit(Some(1))).is_ok());
+    }
+
+    #[test]
+    fn quota_charges_tokens_and_free_slots() {
+        let limiter = Arc::new(RateLimiter::default());
+        let _request = limiter.try_acquire(client(), limit(Some(3))).ok().unwrap();
+        let quota = ClientQuota {
+            limiter: limiter.clone(),
+            client: client(),
+        };
+
+        // Only two slots are left
+        let guard = quota.charge(2, 5).ok().unwrap();
+        assert_eq!(guard.slots(), 2);
+        assert_eq!(in_flight(&limiter), 3);
+
+        // Two tokens are left
+        let err = quota.charge(3, 0).err().unwrap();
+        assert_eq!(err.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        drop(guard);
+        assert_eq!(in_flight(&limiter), 1);
+    }
+
+    #[test]
+    fn quota_over_burst_rejected() {
+        let limiter = Arc::new(RateLimiter::default());
+        let _request = limiter.try_acquire(client(), limit(None)).ok().unwrap();
+        let quota = ClientQuota {
+            limiter,
+            client: client(),
+        };
+        let err = quota.charge(5, 0).err().unwrap();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn guarded_body_holds_slot_until_sent() {
+        let limiter = Arc::new(RateLimiter::default());
+        let guard = limiter.try_acquire(client(), limit(Some(1))).ok().unwrap();
+        let body = guard.hold_until_sent(Body::from("data"));
+        assert_eq!(in_flight(&limiter), 1);
+
+        let data = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(data, "data");
+        assert_eq!(in_flight(&limiter), 0);
+    }
+}
@@ -8,6 +8,8 @@ use axum::{
 };
 use std::convert::Infallible;
 
+use crate::tls::TlsClient;
+
 const X_FORWARDED_PROTO_HEADER_KEY: &str = "X-Forwarded-Proto";
 const X_FORWARDED_SCHEME_HEADER_KEY: &str = "X-Forwarded-Scheme";
 
@@ -52,6 +54,11 @@ where
             return Ok(Scheme(scheme.to_owned()));
         }
 
+        // Served over TLS by the gateway itself
+        if parts.extensions.get::<TlsClient>().is_some() {
+            return Ok(Scheme("https".to_owned()));
+        }
+
         // Fall back to HTTP as default
         Ok(Scheme("http".to_owned()))
     }
@@ -1,31 +1,35 @@
 use futures::{Stream, StreamExt};
 use std::collections::hash_map::{Entry, OccupiedEntry};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use lazy_static::lazy_static;
 use semver::VersionReq;
 use tabled::settings::Style;
 use tabled::Table;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, oneshot, RwLock};
 use tokio::task::JoinHandle;
 
 use subsquid_messages::{
     query_finished, query_result, Ping, Query as QueryMsg, QueryFinished,
-    QueryResult as QueryResultMsg, QuerySubmitted, SizeAndHash,
+    QueryResult as QueryResultMsg, QuerySubmitted, Range, SizeAndHash,
 };
 use subsquid_network_transport::util::{CancellationToken, TaskManager};
 use subsquid_network_transport::PeerId;
 use subsquid_network_transport::{GatewayEvent, GatewayTransportHandle};
 
 use crate::allocations::AllocationsManager;
+use crate::circuit_breaker::Failure;
 use crate::config::{Config, DatasetId};
+use crate::metrics;
 use crate::network_state::NetworkState;
-use crate::query::{Query, QueryResult};
+use crate::query::{Query, QueryRange, QueryResult};
 use crate::task::Task;
 
 const COMP_UNITS_PER_QUERY: u32 = 1;
+/// Waiting queries with less time left are timed out instead of being sent
+const MIN_REMAINING_TIMEOUT: Duration = Duration::from_secs(1);
 
 lazy_static! {
     pub static ref SUPPORTED_WORKER_VERSIONS: VersionReq =
@@ -35,13 +39,29 @@ lazy_static! {
             .expect("Invalid SUPPORTED_WORKER_VERSIONS");
 }
 
+/// Query sent to a saturated worker
+struct WaitingQuery {
+    query: Query,
+    queued_at: Instant,
+    timeout_handle: JoinHandle<()>,
+    timer_id: u64,
+}
+
 pub struct Server<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> {
     incoming_events: S,
     transport_handle: GatewayTransportHandle,
     query_receiver: mpsc::Receiver<Query>,
-    timeout_sender: mpsc::Sender<String>,
-    timeout_receiver: mpsc::Receiver<String>,
+    cancel_receiver: mpsc::Receiver<String>,
+    /// Query IDs with the IDs of their timers. A waiting query gets a new timer once it's sent,
+    /// so messages of the old timer are ignored.
+    timeout_sender: mpsc::Sender<(String, u64)>,
+    timeout_receiver: mpsc::Receiver<(String, u64)>,
+    next_timer_id: u64,
+    probe_sender: mpsc::Sender<oneshot::Sender<()>>,
+    probe_receiver: mpsc::Receiver<oneshot::Sender<()>>,
     tasks: HashMap<String, Task>,
+    /// Queries waiting for the worker to finish one of its queries
+    waiting: HashMap<PeerId, VecDeque<WaitingQuery>>,
     network_state: Arc<RwLock<NetworkState>>,
     allocations_manager: Arc<RwLock<AllocationsManager>>,
     local_peer_id: PeerId,
@@ -54,17 +74,24 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
         incoming_events: S,
         transport_handle: GatewayTransportHandle,
         query_receiver: mpsc::Receiver<Query>,
+        cancel_receiver: mpsc::Receiver<String>,
         network_state: Arc<RwLock<NetworkState>>,
         allocations_manager: Arc<RwLock<AllocationsManager>>,
     ) -> Self {
         let (timeout_sender, timeout_receiver) = mpsc::channel(1000);
+        let (probe_sender, probe_receiver) = mpsc::channel(100);
         Self {
             incoming_events,
             transport_handle,
             query_receiver,
+            cancel_receiver,
             timeout_sender,
             timeout_receiver,
+            next_timer_id: 0,
+            probe_sender,
+            probe_receiver,
             tasks: Default::default(),
+            waiting: Default::default(),
             network_state,
             allocations_manager,
             local_peer_id,
@@ -72,6 +99,11 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
         }
     }
 
+    /// Each sender passed through the channel gets a reply once the main loop picks it up
+    pub fn probe_sender(&self) -> mpsc::Sender<oneshot::Sender<()>> {
+        self.probe_sender.clone()
+    }
+
     pub async fn run(mut self, cancel_token: CancellationToken) {
         log::info!("Starting query server");
         let summary_print_interval = Config::get().summary_print_interval;
@@ -83,9 +115,16 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
                 Some(query) = self.query_receiver.recv() => self.handle_query(query)
                     .await
                     .unwrap_or_else(|e| log::error!("Error handling query: {e:?}")),
-                Some(query_id) = self.timeout_receiver.recv() => self.handle_timeout(query_id)
+                Some(query_id) = self.cancel_receiver.recv() => self.handle_cancel(query_id)
+                    .await
+                    .unwrap_or_else(|e| log::error!("Error handling query cancellation: {e:?}")),
+                Some((query_id, timer_id)) = self.timeout_receiver.recv() => self
+                    .handle_timeout(query_id, timer_id)
                     .await
                     .unwrap_or_else(|e| log::error!("Error handling query timeout: {e:?}")),
+                Some(reply_sender) = self.probe_receiver.recv() => {
+                    let _ = reply_sender.send(());
+                }
                 Some(ev) = self.incoming_events.next() => self.on_incoming_event(ev)
                     .await
                     .unwrap_or_else(|e| log::error!("Error handling incoming message: {e:?}")),
@@ -111,14 +150,37 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
         self.task_manager.spawn_periodic(task, interval);
     }
 
-    fn generate_query_id() -> String {
-        uuid::Uuid::new_v4().to_string()
-    }
-
     async fn handle_query(&mut self, query: Query) -> anyhow::Result<()> {
         log::debug!("Starting query {query:?}");
-        let query_id = Self::generate_query_id();
+
+        // Reject invalid queries before they take a place in the queue
+        // or any compute units are spent
+        let (block_range, saturated) = {
+            let network_state = self.network_state.read().await;
+            let validation = Self::validate_query(
+                &network_state,
+                &query.dataset_id,
+                query.worker_id,
+                &query.query,
+            );
+            (validation, network_state.worker_saturated(&query.worker_id))
+        };
+        let block_range = match block_range {
+            Ok(block_range) => block_range,
+            Err(err) => {
+                log::debug!("Invalid query {}: {err}", query.query_id);
+                let _ = query
+                    .result_sender
+                    .send(QueryResult::BadRequest(err).into());
+                return Ok(());
+            }
+        };
+        if saturated {
+            self.wait_for_worker(query);
+            return Ok(());
+        }
         let Query {
+            query_id,
             dataset_id,
             query,
             worker_id,
@@ -126,7 +188,13 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
             profiling,
             result_sender,
         } = query;
-        let dataset = dataset_id.0;
+
+        // The client has disconnected while the query was waiting in the queue
+        if result_sender.is_closed() {
+            log::debug!("Query {query_id} cancelled before being sent");
+            metrics::query_cancelled(&dataset_id);
+            return Ok(());
+        }
 
         // Check network_state's cache for allocations first, before DB
         if !self
@@ -135,8 +203,8 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
             .await
             .worker_has_allocation(&worker_id)
         {
-            log::warn!("Not enough compute units for worker {worker_id}");
-            let _ = result_sender.send(QueryResult::NoAllocation);
+            log::warn!("Not enough compute units for worker {worker_id}, query {query_id}");
+            let _ = result_sender.send(QueryResult::NoAllocation.into());
             return Ok(());
         }
 
@@ -147,27 +215,40 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
             .try_spend_cus(worker_id, COMP_UNITS_PER_QUERY)
             .await?;
         if !enough_cus {
-            log::warn!("Not enough compute units for worker {worker_id}");
-            let _ = result_sender.send(QueryResult::NoAllocation);
+            log::warn!("Not enough compute units for worker {worker_id}, query {query_id}");
+            let _ = result_sender.send(QueryResult::NoAllocation.into());
             self.network_state
                 .write()
                 .await
                 .no_allocation_for_worker(worker_id); // Save to cache
             return Ok(());
         }
+        {
+            let mut network_state = self.network_state.write().await;
+            network_state.cus_spent(worker_id, COMP_UNITS_PER_QUERY);
+            network_state.task_started(worker_id, &query_id.id);
+        }
 
-        let timeout_handle = self.spawn_timeout_task(&query_id, timeout);
-        let task = Task::new(worker_id, result_sender, timeout_handle);
-        self.tasks.insert(query_id.clone(), task);
+        let (timeout_handle, timer_id) = self.spawn_timeout_task(&query_id.id, timeout);
+        let id = query_id.id.clone();
+        let task = Task::new(
+            query_id,
+            worker_id,
+            dataset_id.clone(),
+            result_sender,
+            timeout_handle,
+            timer_id,
+        );
+        self.tasks.insert(id.clone(), task);
 
         let query_msg = QueryMsg {
-            query_id: Some(query_id.clone()),
-            dataset: Some(dataset.clone()),
+            query_id: Some(id.clone()),
+            dataset: Some(dataset_id.0.clone()),
             query: Some(query.clone()),
             profiling: Some(profiling),
             client_state_json: Some("{}".to_string()), // This is a placeholder field
             signature: vec![],
-            block_range: None,
+            block_range: Some(block_range),
         };
         self.transport_handle.send_query(worker_id, query_msg)?;
 
@@ -176,8 +257,8 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
             let metrics_msg = QuerySubmitted {
                 client_id: self.local_peer_id.to_base58(),
                 worker_id: worker_id.to_base58(),
-                query_id,
-                dataset,
+                query_id: id,
+                dataset: dataset_id.0,
                 query,
                 query_hash,
             };
@@ -187,34 +268,215 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
         Ok(())
     }
 
-    fn spawn_timeout_task(&mut self, query_id: &str, timeout: Duration) -> JoinHandle<()> {
+    /// Keeps the query until the worker finishes one of its queries or the timeout expires
+    fn wait_for_worker(&mut self, query: Query) {
+        log::debug!(
+            "Worker {} is saturated. Query {} is waiting",
+            query.worker_id,
+            query.query_id
+        );
+        let (timeout_handle, timer_id) = self.spawn_timeout_task(&query.query_id.id, query.timeout);
+        self.waiting
+            .entry(query.worker_id)
+            .or_default()
+            .push_back(WaitingQuery {
+                query,
+                queued_at: Instant::now(),
+                timeout_handle,
+                timer_id,
+            });
+    }
+
+    /// Sends the queries waiting for the worker as long as it has free slots
+    async fn send_waiting(&mut self, worker_id: PeerId) {
+        while !self.network_state.read().await.worker_saturated(&worker_id) {
+            let Some(queue) = self.waiting.get_mut(&worker_id) else {
+                return;
+            };
+            let WaitingQuery {
+                mut query,
+                queued_at,
+                timeout_handle,
+                ..
+            } = queue.pop_front().expect("Empty queues are removed");
+            if queue.is_empty() {
+                self.waiting.remove(&worker_id);
+            }
+            // If the timer has already fired, its message is ignored by the timeout handler
+            timeout_handle.abort();
+            let remaining = query.timeout.saturating_sub(queued_at.elapsed());
+            if remaining < MIN_REMAINING_TIMEOUT {
+                log::debug!(
+                    "Query {} timed out waiting for worker {worker_id}",
+                    query.query_id
+                );
+                Self::reply_waiting_timeout(query);
+                continue;
+            }
+            query.timeout = remaining;
+            self.handle_query(query)
+                .await
+                .unwrap_or_else(|e| log::error!("Error handling query: {e:?}"));
+        }
+    }
+
+    /// Returns false if the query isn't waiting for a worker with the given timer
+    fn waiting_timeout(&mut self, query_id: &str, timer_id: u64) -> bool {
+        for (worker_id, queue) in self.waiting.iter_mut() {
+            let Some(index) = queue.iter().position(|waiting| {
+                waiting.query.query_id.id == query_id && waiting.timer_id == timer_id
+            }) else {
+                continue;
+            };
+            let waiting = queue.remove(index).expect("Index is valid");
+            log::debug!("Query {query_id} timed out waiting for worker {worker_id}");
+            Self::reply_waiting_timeout(waiting.query);
+            if queue.is_empty() {
+                let worker_id = *worker_id;
+                self.waiting.remove(&worker_id);
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Returns false if the query isn't waiting for a worker
+    fn cancel_waiting(&mut self, query_id: &str) -> bool {
+        for (worker_id, queue) in self.waiting.iter_mut() {
+            let Some(index) = queue
+                .iter()
+                .position(|waiting| waiting.query.query_id.id == query_id)
+            else {
+                continue;
+            };
+            let waiting = queue.remove(index).expect("Index is valid");
+            waiting.timeout_handle.abort();
+            log::debug!("Query {query_id} cancelled while waiting for worker {worker_id}");
+            metrics::query_cancelled(&waiting.query.dataset_id);
+            if queue.is_empty() {
+                let worker_id = *worker_id;
+                self.waiting.remove(&worker_id);
+            }
+            return true;
+        }
+        false
+    }
+
+    fn reply_waiting_timeout(query: Query) {
+        let result = QueryResult::Timeout("client timeout waiting for the worker".to_string());
+        let _ = query.result_sender.send(result.into());
+    }
+
+    /// Check that the query is valid and its first block is stored by the worker.
+    /// Returns the range of blocks the worker is asked to process,
+    /// which ends with the worker's range at most.
+    fn validate_query(
+        network_state: &NetworkState,
+        dataset_id: &DatasetId,
+        worker_id: PeerId,
+        query: &str,
+    ) -> Result<Range, String> {
+        let QueryRange {
+            from_block,
+            to_block,
+        } = QueryRange::parse(query).map_err(|e| format!("Invalid query: {e}"))?;
+        if let Some(to_block) = to_block {
+            if to_block < from_block {
+                return Err(format!(
+                    "toBlock ({to_block}) is lower than fromBlock ({from_block})"
+                ));
+            }
+        }
+        let range_end = network_state
+            .worker_range_end(dataset_id, &worker_id, from_block)
+            .ok_or_else(|| format!("Worker {worker_id} doesn't have block {from_block}"))?;
+        Ok(Range {
+            begin: from_block,
+            end: to_block.map_or(range_end, |to_block| to_block.min(range_end)),
+        })
+    }
+
+    /// Returns the handle of the timer along with its ID
+    fn spawn_timeout_task(&mut self, query_id: &str, timeout: Duration) -> (JoinHandle<()>, u64) {
         let query_id = query_id.to_string();
+        let timer_id = self.next_timer_id;
+        self.next_timer_id += 1;
         let timeout_sender = self.timeout_sender.clone();
-        tokio::spawn(async move {
+        let handle = tokio::spawn(async move {
             tokio::time::sleep(timeout).await;
-            if timeout_sender.send(query_id).await.is_err() {
+            if timeout_sender.send((query_id, timer_id)).await.is_err() {
                 log::error!("Error sending query timeout")
             }
-        })
+        });
+        (handle, timer_id)
+    }
+
+    async fn handle_timeout(&mut self, query_id: String, timer_id: u64) -> anyhow::Result<()> {
+        if self.waiting_timeout(&query_id, timer_id) {
+            return Ok(());
+        }
+        let task_entry = self.get_task(query_id)?;
+        if task_entry.get().timer_id() != timer_id {
+            // The waiting query has been sent right before its timer fired
+            log::debug!(
+                "Ignoring stale timeout of query {}",
+                task_entry.get().query_id()
+            );
+            return Ok(());
+        }
+        let (query_id, mut task) = task_entry.remove_entry();
+        log::debug!("Query {} execution timed out", task.query_id());
+
+        let task = task.timeout();
+        {
+            let mut network_state = self.network_state.write().await;
+            network_state.worker_failed(task.worker_id, &query_id, Failure::Timeout);
+            network_state.query_finished(&task);
+            network_state.task_finished(task.worker_id, &query_id);
+        }
+        self.send_waiting(task.worker_id).await;
+        if Config::get().send_metrics {
+            let metrics_msg = QueryFinished {
+                client_id: self.local_peer_id.to_base58(),
+                worker_id: task.worker_id.to_base58(),
+                query_id,
+                exec_time_ms: task.exec_time_ms(),
+                result: Some(query_finished::Result::Timeout("client timeout".to_string())),
+            };
+            self.transport_handle.query_finished(metrics_msg)?;
+        }
+        Ok(())
     }
 
-    async fn handle_timeout(&mut self, query_id: String) -> anyhow::Result<()> {
-        log::debug!("Query {query_id} execution timed out");
-        let (query_id, mut task) = self.get_task(query_id)?.remove_entry();
+    /// The task is removed right away, so a late result from the worker is ignored.
+    /// The worker isn't penalized for the cancellation.
+    async fn handle_cancel(&mut self, query_id: String) -> anyhow::Result<()> {
+        if self.cancel_waiting(&query_id) {
+            return Ok(());
+        }
+        // The task may have finished while the cancellation was in the queue
+        let Some((query_id, mut task)) = self.tasks.remove_entry(&query_id) else {
+            return Ok(());
+        };
+        log::debug!("Query {} cancelled by the client", task.query_id());
+        metrics::query_cancelled(task.dataset_id());
 
+        let task = task.cancel();
         self.network_state
             .write()
             .await
-            .greylist_worker(task.worker_id());
+            .task_finished(task.worker_id, &query_id);
+        self.send_waiting(task.worker_id).await;
 
-        let task = task.timeout();
         if Config::get().send_metrics {
             let metrics_msg = QueryFinished {
                 client_id: self.local_peer_id.to_base58(),
                 worker_id: task.worker_id.to_base58(),
                 query_id,
                 exec_time_ms: task.exec_time_ms(),
-                result: Some(query_finished::Result::Timeout("client timeout".to_string())),
+                result: Some(query_finished::Result::Timeout(
+                    "client cancelled".to_string(),
+                )),
             };
             self.transport_handle.query_finished(metrics_msg)?;
         }
@@ -227,7 +489,7 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
             GatewayEvent::QueryResult { peer_id, result } => {
                 self.query_result(peer_id, result).await?
             }
-            GatewayEvent::QueryDropped {query_id} => self.query_dropped(query_id)?
+            GatewayEvent::QueryDropped { query_id } => self.query_dropped(query_id).await?,
         }
         Ok(())
     }
@@ -250,10 +512,16 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
             .update_dataset_states(peer_id, worker_state);
     }
 
-    fn query_dropped(&mut self, query_id: String) -> anyhow::Result<()> {
-        log::debug!("Query {query_id} dropped");
-        let task = self.get_task(query_id)?.remove();
+    async fn query_dropped(&mut self, query_id: String) -> anyhow::Result<()> {
+        let (query_id, task) = self.get_task(query_id)?.remove_entry();
+        log::debug!("Query {} dropped", task.query_id());
+        let worker_id = task.worker_id();
         drop(task); // This will notify the receiver that query has been dropped
+        self.network_state
+            .write()
+            .await
+            .task_finished(worker_id, &query_id);
+        self.send_waiting(worker_id).await;
         Ok(())
     }
 
@@ -265,30 +533,33 @@ impl<S: Stream<Item = GatewayEvent> + Send + Unpin + 'static> Server<S> {
     ) -> anyhow::Result<()> {
         let QueryResultMsg { query_id, result } = result;
         let result = result.ok_or_else(|| anyhow::anyhow!("Result missing"))?;
-        log::debug!("Got result for query {query_id}");
-
         let task_entry = self.get_task(query_id)?;
         let worker_id = task_entry.get().worker_id();
         anyhow::ensure!(peer_id == worker_id, "Invalid message sender");
         let (query_id, mut task) = task_entry.remove_entry();
+        let query_log_id = task.query_id().clone();
+        log::debug!("Got result for query {query_log_id}");
 
         let task = task.result_received(result.clone());
 
-        match &result {
-            // Greylist worker if server error occurred during query execution
-            query_result::Result::ServerError(e) => {
-                log::warn!("Server error returned for query {query_id}: {e}");
-                self.network_state.write().await.greylist_worker(worker_id);
-            }
-            // Add worker to the missing allocations cache
-            query_result::Result::NoAllocation(()) => {
-                self.network_state
-                    .write()
-                    .await
-                    .no_allocation_for_worker(worker_id);
+        {
+            let mut network_state = self.network_state.write().await;
+            network_state.query_finished(&task);
+            match &result {
+                // Greylist worker if server error occurred during query execution
+                query_result::Result::ServerError(e) => {
+                    log::warn!("Server error returned for query {query_log_id}: {e}");
+                    network_state.worker_failed(worker_id, &query_id, Failure::ServerError);
+                }
+                // Add worker to the missing allocations cache
+                query_result::Result::NoAllocation(()) => {
+                    network_state.no_allocation_for_worker(worker_id);
+                }
+                _ => {}
             }
-            _ => {}
+            network_state.task_finished(worker_id, &query_id);
         }
+        self.send_waiting(worker_id).await;
 
         if Config::get().send_metrics {
             // This computes hash, which could take some time, hence spawn_blocking is used here
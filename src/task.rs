@@ -8,13 +8,17 @@ use crate::metrics;
 use subsquid_messages::query_result;
 use subsquid_network_transport::PeerId;
 
-use crate::query::QueryResult;
+use crate::config::DatasetId;
+use crate::query::{QueryId, QueryResponse, QueryResult};
 
 #[derive(Debug)]
 pub struct RunningTask {
+    pub query_id: QueryId,
     pub worker_id: PeerId,
-    result_sender: oneshot::Sender<QueryResult>,
+    pub dataset_id: DatasetId,
+    result_sender: oneshot::Sender<QueryResponse>,
     timeout_handle: JoinHandle<()>,
+    timer_id: u64,
     start_time: Instant,
 }
 
@@ -28,20 +32,36 @@ impl RunningTask {
         self.finish(result.into())
     }
 
+    /// The result receiver is already dropped, so nothing is sent
+    fn cancel(self) -> FinishedTask {
+        self.cancel_timeout();
+        FinishedTask {
+            query_id: self.query_id.id.clone(),
+            worker_id: self.worker_id,
+            exec_time: self.start_time.elapsed(),
+            result: QueryResult::Timeout("client cancelled".to_string()),
+        }
+    }
+
     fn cancel_timeout(&self) {
         self.timeout_handle.abort();
     }
 
     fn finish(self, result: QueryResult) -> FinishedTask {
         let exec_time = self.start_time.elapsed();
-        self.result_sender
-            .send(result.clone())
-            .unwrap_or_else(|_| log::warn!("Query result receiver dropped"));
         let finished_task = FinishedTask {
+            query_id: self.query_id.id.clone(),
             worker_id: self.worker_id,
             exec_time,
             result,
         };
+        let response = QueryResponse {
+            result: finished_task.result.clone(),
+            exec_time: Some(exec_time),
+        };
+        self.result_sender
+            .send(response)
+            .unwrap_or_else(|_| log::warn!("Query {} result receiver dropped", self.query_id));
         metrics::query_finished(&finished_task);
         finished_task
     }
@@ -49,6 +69,7 @@ impl RunningTask {
 
 #[derive(Debug)]
 pub struct FinishedTask {
+    pub query_id: String,
     pub worker_id: PeerId,
     pub exec_time: Duration,
     pub result: QueryResult,
@@ -68,23 +89,45 @@ pub struct Task(Option<RunningTask>);
 
 impl Task {
     pub fn new(
+        query_id: QueryId,
         worker_id: PeerId,
-        result_sender: oneshot::Sender<QueryResult>,
+        dataset_id: DatasetId,
+        result_sender: oneshot::Sender<QueryResponse>,
         timeout_handle: JoinHandle<()>,
+        timer_id: u64,
     ) -> Self {
         Self(Some(RunningTask {
+            query_id,
             worker_id,
+            dataset_id,
             result_sender,
             timeout_handle,
+            timer_id,
             start_time: Instant::now(),
         }))
     }
 
+    /// ID of the timer started when the task was sent.
+    /// Panics if task is already finished
+    pub fn timer_id(&self) -> u64 {
+        self.0.as_ref().expect("Task already finished").timer_id
+    }
+
     /// Panics if task is already finished
     pub fn worker_id(&self) -> PeerId {
         self.0.as_ref().expect("Task already finished").worker_id
     }
 
+    /// Panics if task is already finished
+    pub fn query_id(&self) -> &QueryId {
+        &self.0.as_ref().expect("Task already finished").query_id
+    }
+
+    /// Panics if task is already finished
+    pub fn dataset_id(&self) -> &DatasetId {
+        &self.0.as_ref().expect("Task already finished").dataset_id
+    }
+
     /// Panics if task is already finished
     pub fn timeout(&mut self) -> FinishedTask {
         self.0
@@ -102,6 +145,15 @@ impl Task {
             .expect("Task already finished")
             .result_received(result)
     }
+
+    /// Panics if task is already finished
+    pub fn cancel(&mut self) -> FinishedTask {
+        self.0
+            .borrow_mut()
+            .take()
+            .expect("Task already finished")
+            .cancel()
+    }
 }
 
 impl Drop for Task {
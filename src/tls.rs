@@ -0,0 +1,167 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::BoxFuture;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower_layer::Layer;
+
+/// How often the certificate files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// CA bundle used to verify client certificates
+    pub client_ca_path: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        [
+            Some(&self.cert_path),
+            Some(&self.key_path),
+            self.client_ca_path.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(PathBuf::as_path)
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Connection details added to every request received over TLS
+#[derive(Debug, Clone)]
+pub struct TlsClient {
+    /// Subject of the verified client certificate, if the client has presented one
+    pub cert_subject: Option<String>,
+}
+
+/// Performs the TLS handshake and exposes the client certificate to the handlers
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, TlsClient>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let cert_subject = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| cert_subject(cert));
+            let client = TlsClient { cert_subject };
+            Ok((stream, Extension(client).layer(service)))
+        })
+    }
+}
+
+fn cert_subject(cert: &CertificateDer) -> Option<String> {
+    match x509_parser::parse_x509_certificate(cert) {
+        Ok((_, cert)) => Some(cert.subject().to_string()),
+        Err(e) => {
+            log::warn!("Couldn't parse client certificate: {e}");
+            None
+        }
+    }
+}
+
+/// Builds the server config from the certificate files. HTTP/2 is negotiated with ALPN.
+/// If a client CA is given, client certificates are verified against it, but not required,
+/// so that clients can still authenticate with API keys.
+pub fn server_config(files: &TlsFiles) -> anyhow::Result<ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &files.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config =
+        builder.with_single_cert(load_certs(&files.cert_path)?, load_key(&files.key_path)?)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let data = std::fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut data.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    anyhow::ensure!(!certs.is_empty(), "No certificates in {}", path.display());
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let data = std::fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    rustls_pemfile::private_key(&mut data.as_slice())?
+        .ok_or_else(|| anyhow::anyhow!("No private key in {}", path.display()))
+}
+
+/// Reloads the certificates whenever any of the files changes.
+/// Connections are served with the old certificates until the new ones are loaded successfully.
+pub async fn reload_on_change(files: TlsFiles, config: RustlsConfig) {
+    let mut last_modified = files.modified();
+    loop {
+        tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+        let modified = files.modified();
+        if modified == last_modified {
+            continue;
+        }
+        match server_config(&files) {
+            Ok(server_config) => {
+                config.reload_from_config(Arc::new(server_config));
+                last_modified = modified;
+                log::info!("TLS certificates reloaded");
+            }
+            Err(e) => log::error!("Couldn't reload TLS certificates: {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, TimestampSeconds};
+use tokio_rusqlite::Connection;
+use utoipa::ToSchema;
+
+use subsquid_network_transport::PeerId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OverrideKind {
+    /// Worker is only used if no other worker is available
+    Greylist,
+    /// Worker is never used
+    Ban,
+}
+
+impl OverrideKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OverrideKind::Greylist => "greylist",
+            OverrideKind::Ban => "ban",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "greylist" => Some(OverrideKind::Greylist),
+            "ban" => Some(OverrideKind::Ban),
+            _ => None,
+        }
+    }
+}
+
+/// Worker status set manually by an admin
+#[serde_as]
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WorkerOverride {
+    #[schema(value_type = String)]
+    pub worker_id: PeerId,
+    pub kind: OverrideKind,
+    pub reason: String,
+    /// ID of the API key used to set the override
+    pub set_by: String,
+    /// Unix timestamp in seconds
+    #[serde_as(as = "TimestampSeconds<i64>")]
+    #[schema(value_type = i64)]
+    pub created_at: SystemTime,
+    /// Unix timestamp in seconds, `null` if the override never expires
+    #[serde_as(as = "Option<TimestampSeconds<i64>>")]
+    #[schema(value_type = Option<i64>)]
+    pub expires_at: Option<SystemTime>,
+}
+
+impl WorkerOverride {
+    /// Returns `None` if the expiration time doesn't fit into `SystemTime`
+    pub fn new(
+        worker_id: PeerId,
+        kind: OverrideKind,
+        reason: String,
+        set_by: String,
+        duration: Option<Duration>,
+    ) -> Option<Self> {
+        let created_at = SystemTime::now();
+        let expires_at = match duration {
+            Some(d) => Some(created_at.checked_add(d)?),
+            None => None,
+        };
+        Some(Self {
+            worker_id,
+            kind,
+            reason,
+            set_by,
+            created_at,
+            expires_at,
+        })
+    }
+
+    pub fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|t| t <= SystemTime::now())
+    }
+}
+
+/// Keeps worker overrides on disk, so that they survive restarts
+pub struct WorkerOverridesStore {
+    db_conn: Connection,
+}
+
+impl WorkerOverridesStore {
+    pub async fn new(db_path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        log::info!("Initializing worker overrides store");
+        let db_conn = Connection::open(&db_path).await?;
+        db_conn
+            .call(|conn| {
+                conn.execute(sql::OVERRIDES_TABLE, ())?;
+                Ok(())
+            })
+            .await?;
+        Ok(Self { db_conn })
+    }
+
+    /// Load all overrides which haven't expired yet, deleting the expired ones
+    pub async fn load(&self) -> anyhow::Result<Vec<WorkerOverride>> {
+        self.purge_expired().await?;
+        let rows: Vec<(String, String, String, String, i64, Option<i64>)> = self
+            .db_conn
+            .call(|conn| {
+                let mut stmt = conn.prepare(sql::GET_OVERRIDES)?;
+                let rows = stmt
+                    .query_map((), |row| {
+                        Ok((
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                            row.get(5)?,
+                        ))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+
+        let overrides = rows
+            .into_iter()
+            .filter_map(
+                |(worker_id, kind, reason, set_by, created_at, expires_at)| {
+                    let worker_override = WorkerOverride {
+                        worker_id: worker_id.parse().ok()?,
+                        kind: OverrideKind::parse(&kind)?,
+                        reason,
+                        set_by,
+                        created_at: from_timestamp(created_at),
+                        expires_at: expires_at.map(from_timestamp),
+                    };
+                    (!worker_override.expired()).then_some(worker_override)
+                },
+            )
+            .collect();
+        Ok(overrides)
+    }
+
+    pub async fn save(&self, worker_override: &WorkerOverride) -> anyhow::Result<()> {
+        let params = (
+            worker_override.worker_id.to_string(),
+            worker_override.kind.as_str(),
+            worker_override.reason.clone(),
+            worker_override.set_by.clone(),
+            to_timestamp(worker_override.created_at),
+            worker_override.expires_at.map(to_timestamp),
+        );
+        self.db_conn
+            .call(move |conn| {
+                conn.execute(sql::SAVE_OVERRIDE, params)?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Delete all expired overrides, returns the number of deleted rows
+    pub async fn purge_expired(&self) -> anyhow::Result<usize> {
+        let now = to_timestamp(SystemTime::now());
+        let purged = self
+            .db_conn
+            .call(move |conn| Ok(conn.execute(sql::PURGE_EXPIRED, [now])?))
+            .await?;
+        Ok(purged)
+    }
+
+    /// Returns `false` if there was no override for the worker
+    pub async fn remove(&self, worker_id: PeerId) -> anyhow::Result<bool> {
+        let worker_id = worker_id.to_string();
+        let removed = self
+            .db_conn
+            .call(move |conn| Ok(conn.execute(sql::REMOVE_OVERRIDE, [worker_id])? > 0))
+            .await?;
+        Ok(removed)
+    }
+}
+
+fn to_timestamp(t: SystemTime) -> i64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn from_timestamp(secs: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+mod sql {
+    pub const OVERRIDES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS worker_overrides(
+        peer_id STRING PRIMARY KEY,
+        kind STRING NOT NULL,
+        reason STRING NOT NULL,
+        set_by STRING NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER
+    )";
+
+    pub const GET_OVERRIDES: &str = "
+    SELECT peer_id, kind, reason, set_by, created_at, expires_at FROM worker_overrides
+    ";
+
+    pub const SAVE_OVERRIDE: &str = "
+    INSERT OR REPLACE INTO worker_overrides (peer_id, kind, reason, set_by, created_at, expires_at)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+    ";
+
+    pub const REMOVE_OVERRIDE: &str = "DELETE FROM worker_overrides WHERE peer_id = ?1";
+
+    pub const PURGE_EXPIRED: &str =
+        "DELETE FROM worker_overrides WHERE expires_at IS NOT NULL AND expires_at <= ?1";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_override(duration: Option<Duration>) -> Option<WorkerOverride> {
+        WorkerOverride::new(
+            PeerId::random(),
+            OverrideKind::Ban,
+            "test".to_string(),
+            "admin".to_string(),
+            duration,
+        )
+    }
+
+    #[test]
+    fn expiration() {
+        let permanent = new_override(None).unwrap();
+        assert_eq!(permanent.expires_at, None);
+        assert!(!permanent.expired());
+
+        let temporary = new_override(Some(Duration::from_secs(60))).unwrap();
+        assert!(!temporary.expired());
+
+        let expired = new_override(Some(Duration::ZERO)).unwrap();
+        assert!(expired.expired());
+    }
+
+    #[test]
+    fn overflowing_duration_rejected() {
+        assert!(new_override(Some(Duration::from_secs(u64::MAX))).is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use subsquid_network_transport::PeerId;
+
+/// Number of the most recent execution times kept for percentiles
+const RECENT_SAMPLES: usize = 100;
+
+/// Execution times of the queries sent to a worker
+#[derive(Debug, Default, Clone)]
+pub struct WorkerLatency {
+    /// Exponentially weighted moving average in seconds
+    ewma: Option<f64>,
+    recent: VecDeque<Duration>,
+    total_samples: u64,
+}
+
+impl WorkerLatency {
+    /// `alpha` is the weight of the new sample in the moving average
+    pub fn observe(&mut self, exec_time: Duration, alpha: f64) {
+        let secs = exec_time.as_secs_f64();
+        self.ewma = Some(match self.ewma {
+            Some(ewma) => alpha * secs + (1.0 - alpha) * ewma,
+            None => secs,
+        });
+        if self.recent.len() == RECENT_SAMPLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(exec_time);
+        self.total_samples += 1;
+    }
+
+    pub fn ewma(&self) -> Option<Duration> {
+        self.ewma.map(Duration::from_secs_f64)
+    }
+
+    /// `q`-th quantile of the recent execution times, `q` being between 0 and 1
+    fn percentile(sorted: &[Duration], q: f64) -> Option<Duration> {
+        let last = sorted.len().checked_sub(1)?;
+        let index = (q * last as f64).round() as usize;
+        Some(sorted[index])
+    }
+
+    pub fn summary(&self, worker_id: PeerId) -> LatencySummary {
+        let mut sorted: Vec<_> = self.recent.iter().copied().collect();
+        sorted.sort_unstable();
+        let ms = |duration: Option<Duration>| duration.map(|d| d.as_millis() as u64);
+        LatencySummary {
+            worker_id: worker_id.to_string(),
+            samples: self.total_samples,
+            ewma_ms: ms(self.ewma()),
+            p50_ms: ms(Self::percentile(&sorted, 0.5)),
+            p90_ms: ms(Self::percentile(&sorted, 0.9)),
+            p99_ms: ms(Self::percentile(&sorted, 0.99)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LatencySummary {
+    worker_id: String,
+    /// Number of execution times observed since the gateway started
+    samples: u64,
+    /// Moving average of the execution time
+    ewma_ms: Option<u64>,
+    /// Percentiles of the last 100 execution times
+    p50_ms: Option<u64>,
+    p90_ms: Option<u64>,
+    p99_ms: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn ewma_weights_new_samples() {
+        let mut latency = WorkerLatency::default();
+        assert_eq!(latency.ewma(), None);
+        latency.observe(ms(1000), 0.25);
+        assert_eq!(latency.ewma(), Some(ms(1000)));
+        latency.observe(ms(2000), 0.25);
+        assert_eq!(latency.ewma(), Some(ms(1250)));
+    }
+
+    #[test]
+    fn percentiles() {
+        let sorted: Vec<_> = (1..=100).map(ms).collect();
+        assert_eq!(WorkerLatency::percentile(&sorted, 0.0), Some(ms(1)));
+        assert_eq!(WorkerLatency::percentile(&sorted, 0.5), Some(ms(51)));
+        assert_eq!(WorkerLatency::percentile(&sorted, 0.9), Some(ms(90)));
+        assert_eq!(WorkerLatency::percentile(&sorted, 1.0), Some(ms(100)));
+        assert_eq!(WorkerLatency::percentile(&[ms(7)], 0.99), Some(ms(7)));
+        assert_eq!(WorkerLatency::percentile(&[], 0.5), None);
+    }
+
+    #[test]
+    fn summary_uses_recent_samples() {
+        let mut latency = WorkerLatency::default();
+        for i in 0..150 {
+            // Older samples are slower and fall out of the window
+            let exec_time = if i < 50 { ms(10_000) } else { ms(i) };
+            latency.observe(exec_time, 0.5);
+        }
+        let summary = latency.summary(PeerId::random());
+        assert_eq!(summary.samples, 150);
+        assert_eq!(latency.recent.len(), RECENT_SAMPLES);
+        assert_eq!(summary.p50_ms, Some(100));
+        assert_eq!(summary.p99_ms, Some(148));
+    }
+}
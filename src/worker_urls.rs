@@ -0,0 +1,309 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use utoipa::IntoParams;
+
+use subsquid_network_transport::PeerId;
+
+use crate::api_error::{ApiError, Path};
+use crate::config::{Config, DatasetId};
+
+/// Reasons for a worker URL token to be rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    Missing,
+    Malformed,
+    Expired,
+    InvalidSignature,
+    BlockMismatch,
+}
+
+impl TokenError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenError::Missing => "missing",
+            TokenError::Malformed => "malformed",
+            TokenError::Expired => "expired",
+            TokenError::InvalidSignature => "invalid_signature",
+            TokenError::BlockMismatch => "block_mismatch",
+        }
+    }
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Issues and verifies tokens of the URLs returned by the `get_worker` endpoint.
+/// A token has the form `<start_block>.<expires_at>.<signature>`, where the signature
+/// is an HMAC-SHA256 of the dataset, worker, start block and expiry timestamp.
+pub struct UrlSigner {
+    /// Shared by all gateway instances. URLs are not signed without it.
+    key: Option<Vec<u8>>,
+}
+
+impl UrlSigner {
+    /// Without a secret, URLs are not signed, because tokens signed with a random key would only
+    /// be valid for this gateway instance until it restarts. Unsigned URLs then have to be allowed.
+    pub fn new(secret: Option<String>) -> anyhow::Result<Self> {
+        let key = match secret {
+            Some(secret) => Some(secret.into_bytes()),
+            None => {
+                anyhow::ensure!(
+                    Config::get().worker_urls.allow_unsigned,
+                    "--worker-url-secret is required unless worker_urls.allow_unsigned is set"
+                );
+                log::warn!("No worker URL secret configured. Worker URLs are not signed");
+                None
+            }
+        };
+        Ok(Self { key })
+    }
+
+    /// Returns `None` if no secret is configured
+    pub fn sign(
+        &self,
+        dataset_id: &DatasetId,
+        worker_id: &PeerId,
+        start_block: u32,
+        ttl: Duration,
+    ) -> Option<String> {
+        let key = self.key.as_ref()?;
+        let expires_at = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let signature = Self::mac(key, dataset_id, worker_id, start_block, expires_at)
+            .finalize()
+            .into_bytes();
+        Some(format!(
+            "{start_block}.{expires_at}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+
+    /// Checks the token and returns the start block it has been issued for.
+    /// Without a secret, tokens can't be checked and are treated as missing.
+    pub fn verify(
+        &self,
+        token: &str,
+        dataset_id: &DatasetId,
+        worker_id: &PeerId,
+    ) -> Result<u32, TokenError> {
+        let key = self.key.as_ref().ok_or(TokenError::Missing)?;
+        let mut parts = token.splitn(3, '.');
+        let (Some(start_block), Some(expires_at), Some(signature)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TokenError::Malformed);
+        };
+        let start_block: u32 = start_block.parse().map_err(|_| TokenError::Malformed)?;
+        let expires_at: u64 = expires_at.parse().map_err(|_| TokenError::Malformed)?;
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+
+        Self::mac(key, dataset_id, worker_id, start_block, expires_at)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::InvalidSignature)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        if now > expires_at {
+            return Err(TokenError::Expired);
+        }
+        Ok(start_block)
+    }
+
+    fn mac(
+        key: &[u8],
+        dataset_id: &DatasetId,
+        worker_id: &PeerId,
+        start_block: u32,
+        expires_at: u64,
+    ) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key");
+        mac.update(format!("{dataset_id}/{worker_id}/{start_block}/{expires_at}").as_bytes());
+        mac
+    }
+}
+
+/// Checks that the query starts within the worker's range containing `signed_block`,
+/// the block the URL has been issued for. Without a known range, only `signed_block` matches.
+pub fn check_start_block(
+    signed_block: u32,
+    range_end: Option<u32>,
+    start_block: u32,
+) -> Result<(), TokenError> {
+    let range_end = range_end.unwrap_or(signed_block);
+    if (signed_block..=range_end).contains(&start_block) {
+        Ok(())
+    } else {
+        Err(TokenError::BlockMismatch)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TokenParams {
+    /// Token included in the URL returned by the worker endpoint.
+    /// Optional only if `worker_urls.allow_unsigned` is set in the config.
+    token: Option<String>,
+}
+
+/// Extractor of the `/query/<dataset_id>/<worker_id>` path, verifying the token of the URL
+pub struct WorkerUrl {
+    pub dataset_id: DatasetId,
+    pub worker_id: PeerId,
+    /// Start block the URL has been issued for
+    pub token: Result<u32, TokenError>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for WorkerUrl
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path((dataset_id, worker_id)) =
+            Path::<(DatasetId, PeerId)>::from_request_parts(parts, state).await?;
+        let token = Query::<TokenParams>::from_request_parts(parts, state)
+            .await
+            .ok()
+            .and_then(|Query(params)| params.token);
+        let url_signer = parts
+            .extensions
+            .get::<Arc<UrlSigner>>()
+            .expect("URL signer should be added to extensions");
+        let token = match token {
+            None => Err(TokenError::Missing),
+            Some(token) => url_signer.verify(&token, &dataset_id, &worker_id),
+        };
+        Ok(WorkerUrl {
+            dataset_id,
+            worker_id,
+            token,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn signer(secret: &str) -> UrlSigner {
+        UrlSigner {
+            key: Some(secret.as_bytes().to_vec()),
+        }
+    }
+
+    fn dataset() -> DatasetId {
+        DatasetId::from_url("s3://ethereum-mainnet")
+    }
+
+    #[test]
+    fn round_trip() {
+        let signer = signer("secret");
+        let worker_id = PeerId::random();
+        let token = signer.sign(&dataset(), &worker_id, 1000, TTL).unwrap();
+        assert_eq!(signer.verify(&token, &dataset(), &worker_id), Ok(1000));
+    }
+
+    #[test]
+    fn unsigned_without_secret() {
+        let signer = UrlSigner { key: None };
+        let worker_id = PeerId::random();
+        assert_eq!(signer.sign(&dataset(), &worker_id, 1000, TTL), None);
+        assert_eq!(
+            signer.verify("1000.0.abc", &dataset(), &worker_id),
+            Err(TokenError::Missing)
+        );
+    }
+
+    #[test]
+    fn expired_token_rejected() {
+        let signer = signer("secret");
+        let worker_id = PeerId::random();
+        let expires_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 1;
+        let key = signer.key.as_deref().unwrap();
+        let signature = UrlSigner::mac(key, &dataset(), &worker_id, 1000, expires_at)
+            .finalize()
+            .into_bytes();
+        let token = format!(
+            "1000.{expires_at}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(signature)
+        );
+        assert_eq!(
+            signer.verify(&token, &dataset(), &worker_id),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn tampered_token_rejected() {
+        let signer = signer("secret");
+        let worker_id = PeerId::random();
+        let token = signer.sign(&dataset(), &worker_id, 1000, TTL).unwrap();
+        let check = |token: &str, worker_id: &PeerId| signer.verify(token, &dataset(), worker_id);
+
+        // Different start block
+        let tampered = token.replacen("1000", "2000", 1);
+        assert_eq!(
+            check(&tampered, &worker_id),
+            Err(TokenError::InvalidSignature)
+        );
+        // Issued for another worker
+        assert_eq!(
+            check(&token, &PeerId::random()),
+            Err(TokenError::InvalidSignature)
+        );
+        // Signed with another secret
+        let other = self::signer("other")
+            .sign(&dataset(), &worker_id, 1000, TTL)
+            .unwrap();
+        assert_eq!(check(&other, &worker_id), Err(TokenError::InvalidSignature));
+
+        assert_eq!(check("1000.abc", &worker_id), Err(TokenError::Malformed));
+        assert_eq!(check("x.1.abc", &worker_id), Err(TokenError::Malformed));
+        assert_eq!(check("1000.1.!!", &worker_id), Err(TokenError::Malformed));
+    }
+
+    #[test]
+    fn start_block_within_worker_range() {
+        assert_eq!(check_start_block(1000, Some(2000), 1000), Ok(()));
+        assert_eq!(check_start_block(1000, Some(2000), 2000), Ok(()));
+        assert_eq!(
+            check_start_block(1000, Some(2000), 999),
+            Err(TokenError::BlockMismatch)
+        );
+        assert_eq!(
+            check_start_block(1000, Some(2000), 2001),
+            Err(TokenError::BlockMismatch)
+        );
+        assert_eq!(check_start_block(1000, None, 1000), Ok(()));
+        assert_eq!(
+            check_start_block(1000, None, 1001),
+            Err(TokenError::BlockMismatch)
+        );
+    }
+}